@@ -0,0 +1,434 @@
+//! A computer player that searches placements.
+//!
+//! For the piece in play, [`AiBot`] tries every column it can reach and every
+//! rotation, drops the piece on a copy of the field and resolves the chain
+//! with the game's own erase and fall rules. On each result it does the same
+//! for the next piece, and keeps the placement that leads to the best
+//! position. Positions are scored by [`Weights`]: jewels erased and chain
+//! steps made, the height of the stack, pairs of same-colored jewels that a
+//! later piece can complete, and jewels with no same-colored neighbour.
+//! The chosen placement is played out as a queue of commands, one per frame.
+//!
+//! Weights can be read from a file of `key = value` lines, like rule files:
+//!
+//! ```text
+//! erased = 10
+//! chain = 60
+//! height = 4
+//! pairs = 6
+//! isolated = 4
+//! ```
+
+use crate::bot::Bot;
+use crate::config;
+use crate::model::{Command, Game, Input, State, EMPTY};
+use std::collections::VecDeque;
+use std::path::Path;
+
+/// 負けになる置き方の評価値
+const LOSS: i32 = -1_000_000;
+
+/// 盤面の評価に使う重み。height と isolated は引く
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weights {
+    pub erased: i32,   // 消した宝石1個あたり
+    pub chain: i32,    // 2段目からの連鎖1段あたり
+    pub height: i32,   // 一番高い列の高さの2乗あたり
+    pub pairs: i32,    // 同じ色が2個並んだところ1つあたり
+    pub isolated: i32, // まわり8マスに同じ色が無い宝石1個あたり
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            erased: 10,
+            chain: 60,
+            height: 4,
+            pairs: 6,
+            isolated: 4,
+        }
+    }
+}
+
+impl Weights {
+    pub fn parse(text: &str) -> Result<Weights, String> {
+        let mut weights = Weights::default();
+        for (key, value) in config::parse(text)? {
+            weights.set(&key, &value)?;
+        }
+        Ok(weights)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "erased" => self.erased = config::parse_value(key, value)?,
+            "chain" => self.chain = config::parse_value(key, value)?,
+            "height" => self.height = config::parse_value(key, value)?,
+            "pairs" => self.pairs = config::parse_value(key, value)?,
+            "isolated" => self.isolated = config::parse_value(key, value)?,
+            _ => return Err(format!("unknown weight: {}", key)),
+        }
+        Ok(())
+    }
+
+    /// parse で読み込める形式で書き出す
+    pub fn to_config(&self) -> String {
+        format!(
+            "erased = {}\nchain = {}\nheight = {}\npairs = {}\nisolated = {}\n",
+            self.erased, self.chain, self.height, self.pairs, self.isolated
+        )
+    }
+
+    pub fn load(path: &Path) -> Result<Weights, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Weights::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// ピースの置き方。rotation は Rotate を押す回数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub x: usize,
+    pub rotation: usize,
+}
+
+impl Placement {
+    /// from_x から置くまでのコマンド。最後にハードドロップする
+    pub fn commands(&self, from_x: usize, block_len: usize) -> Vec<Command> {
+        let mut commands = Vec::new();
+        // 半分を超えるなら逆回転の方が早い
+        if self.rotation * 2 <= block_len {
+            commands.extend(std::iter::repeat_n(Command::Rotate, self.rotation));
+        } else {
+            commands.extend(std::iter::repeat_n(
+                Command::RotateReverse,
+                block_len - self.rotation,
+            ));
+        }
+        let (dir, count) = if self.x < from_x {
+            (Command::Left, from_x - self.x)
+        } else {
+            (Command::Right, self.x - from_x)
+        };
+        commands.extend(std::iter::repeat_n(dir, count));
+        commands.push(Command::HardDrop);
+        commands
+    }
+}
+
+/// 置いて連鎖を終えたあとの盤面
+struct Outcome {
+    board: Game,
+    erased: i32,
+    chain: i32,
+}
+
+/// 探索用のゲーム。フィールドとルールだけを持ち、乱数やリプレイの記録は持たない
+fn board_of(game: &Game) -> Game {
+    Game {
+        rules: game.rules.clone(),
+        field: game.field.clone(),
+        check_erase_result: game.check_erase_result.clone(),
+        piece_falling: game.piece_falling.clone(),
+        level: game.level,
+        ..Default::default()
+    }
+}
+
+/// (x, y) にあるピースから、横に動いて届く置き方をすべて返す
+fn placements(board: &Game, piece: &[i32], x: usize, y: usize) -> Vec<Placement> {
+    let rules = &board.rules;
+    let bottom_y = y + rules.block_len - 1;
+    if bottom_y >= rules.field_h || board.field[bottom_y][x] != EMPTY {
+        return Vec::new();
+    }
+    // 途中の列がふさがっていたらその先には行けない
+    let mut left = x;
+    while left > 0 && board.field[bottom_y][left - 1] == EMPTY {
+        left -= 1;
+    }
+    let mut right = x;
+    while right + 1 < rules.field_w && board.field[bottom_y][right + 1] == EMPTY {
+        right += 1;
+    }
+
+    // 同じ並びになる回転は1つにまとめる
+    let mut rotated = piece.to_vec();
+    let mut seen: Vec<Vec<i32>> = Vec::new();
+    let mut rotations = Vec::new();
+    for rotation in 0..rules.block_len {
+        if !seen.contains(&rotated) {
+            seen.push(rotated.clone());
+            rotations.push(rotation);
+        }
+        rotated.rotate_right(1);
+    }
+    rotations
+        .into_iter()
+        .flat_map(|rotation| (left..=right).map(move |x| Placement { x, rotation }))
+        .collect()
+}
+
+/// y の高さから落として着地させ、連鎖が終わるまで進める
+fn drop_piece(board: &Game, piece: &[i32], placement: Placement, y: usize) -> Option<Outcome> {
+    let mut board = board.clone();
+    board.current = piece.to_vec();
+    for _ in 0..placement.rotation {
+        board.rotate();
+    }
+    board.current_x = placement.x;
+    board.current_y = y;
+    while !board.is_intersect() {
+        board.current_y += 1;
+    }
+    board.current_y = board.current_y.checked_sub(1)?;
+    board.settle();
+
+    board.combo = -1;
+    let mut erasing = board.check_magic() || board.check_erase();
+    while erasing {
+        board.combo += 1;
+        board.actually_erase();
+        while board.check_piece_fall() {
+            board.actually_piece_fall();
+        }
+        erasing = board.check_erase();
+    }
+    board.check_gameover();
+    Some(Outcome {
+        erased: board.total_erased,
+        chain: board.combo + 1,
+        board,
+    })
+}
+
+/// 連鎖が終わったあとの盤面の良さ
+pub fn evaluate(board: &Game, weights: &Weights) -> i32 {
+    if board.is_over {
+        return LOSS;
+    }
+    let rules = &board.rules;
+    let field = &board.field;
+    let color_at = |x: i32, y: i32| {
+        if 0 <= x && x < rules.field_w as i32 && 0 <= y && y < rules.field_h as i32 {
+            field[y as usize][x as usize]
+        } else {
+            EMPTY
+        }
+    };
+
+    let height = (0..rules.field_w)
+        .map(|x| {
+            let top = (0..rules.field_h).find(|&y| field[y][x] != EMPTY);
+            top.map_or(0, |y| rules.field_h - y) as i32
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut pairs = 0;
+    let mut isolated = 0;
+    for y in 0..rules.field_h as i32 {
+        for x in 0..rules.field_w as i32 {
+            let color = color_at(x, y);
+            if color == EMPTY {
+                continue;
+            }
+            // 右・右下・下・右上だけ見て、同じ組を2回数えないようにする
+            for (dx, dy) in [(1, 0), (1, 1), (0, 1), (1, -1)] {
+                if color_at(x + dx, y + dy) == color {
+                    pairs += 1;
+                }
+            }
+            let has_neighbour = (-1..=1)
+                .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                .any(|(dx, dy)| (dx, dy) != (0, 0) && color_at(x + dx, y + dy) == color);
+            if !has_neighbour {
+                isolated += 1;
+            }
+        }
+    }
+
+    pairs * weights.pairs - isolated * weights.isolated - height * height * weights.height
+}
+
+/// 消した個数と連鎖の得点
+fn reward(outcome: &Outcome, weights: &Weights) -> i32 {
+    outcome.erased * weights.erased + (outcome.chain - 1).max(0) * weights.chain
+}
+
+/// 操作中のピースの一番よい置き方。次のピースの置き方まで読む
+pub fn best_placement(game: &Game, weights: &Weights) -> Option<Placement> {
+    if game.current.iter().all(|&v| v == EMPTY) {
+        return None;
+    }
+    let board = board_of(game);
+    let mut best: Option<(i32, Placement)> = None;
+    for placement in placements(&board, &game.current, game.current_x, game.current_y) {
+        let Some(outcome) = drop_piece(&board, &game.current, placement, game.current_y) else {
+            continue;
+        };
+        let value = if outcome.board.is_over {
+            LOSS
+        } else {
+            reward(&outcome, weights) + lookahead(&outcome.board, &game.next, weights)
+        };
+        if best.is_none_or(|(best_value, _)| value > best_value) {
+            best = Some((value, placement));
+        }
+    }
+    best.map(|(_, placement)| placement)
+}
+
+/// 次のピースを置いたあとの一番よい評価値
+fn lookahead(board: &Game, next: &[i32], weights: &Weights) -> i32 {
+    // パズルでピースを使い切ったら今の盤面で決める
+    if next.iter().all(|&v| v == EMPTY) {
+        return evaluate(board, weights);
+    }
+    placements(board, next, board.spawn_x(), 0)
+        .into_iter()
+        .filter_map(|placement| drop_piece(board, next, placement, 0))
+        .map(|outcome| reward(&outcome, weights) + evaluate(&outcome.board, weights))
+        .max()
+        .unwrap_or(LOSS)
+}
+
+/// 探索して決めた置き方をコマンドに直して操作する
+pub struct AiBot {
+    weights: Weights,
+    delay: i32, // コマンドとコマンドの間に待つフレーム数。0 なら毎フレーム
+    wait: i32,
+    plan: VecDeque<Command>,
+    planned_for: Option<usize>, // 計画を立てたピース。Game::settles で見分ける
+}
+
+impl AiBot {
+    pub fn new(weights: Weights) -> Self {
+        AiBot {
+            weights,
+            delay: 0,
+            wait: 0,
+            plan: VecDeque::new(),
+            planned_for: None,
+        }
+    }
+
+    /// 人が相手をできるように、操作の間を空ける
+    pub fn with_delay(mut self, delay: i32) -> Self {
+        self.delay = delay.max(0);
+        self
+    }
+}
+
+impl Bot for AiBot {
+    fn input(&mut self, game: &Game) -> Input {
+        let counting_down = game.timer.as_ref().is_some_and(|timer| timer.countdown > 0);
+        if game.is_over || game.paused || counting_down || game.state != State::Controllable {
+            return Input::NONE;
+        }
+        let piece = game.settles;
+        if self.planned_for != Some(piece) {
+            self.planned_for = Some(piece);
+            self.wait = self.delay;
+            self.plan = best_placement(game, &self.weights)
+                .map(|placement| placement.commands(game.current_x, game.rules.block_len))
+                .unwrap_or_default()
+                .into();
+        }
+        if self.wait > 0 {
+            self.wait -= 1;
+            return Input::NONE;
+        }
+        self.wait = self.delay;
+        self.plan.pop_front().map_or(Input::NONE, Input::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+    use crate::sim::run_bot;
+
+    #[test]
+    fn test_weights() {
+        let weights = Weights {
+            chain: 100,
+            ..Default::default()
+        };
+        assert_eq!(Weights::parse(&weights.to_config()), Ok(weights));
+        assert_eq!(Weights::parse("height = 9").unwrap().height, 9);
+        assert!(Weights::parse("speed = 1").is_err());
+        assert!(Weights::parse("height = \"high\"").is_err());
+    }
+
+    #[test]
+    fn test_placement() {
+        let placement = Placement { x: 1, rotation: 2 };
+        assert_eq!(
+            placement.commands(3, 3),
+            vec![
+                Command::RotateReverse,
+                Command::Left,
+                Command::Left,
+                Command::HardDrop
+            ]
+        );
+
+        // 4段積んだ列の向こうへは行けない
+        let mut game = Game::with_seed(0, Rules::default());
+        for y in 12..16 {
+            game.field[y][1] = 1 + y as i32 % 2;
+        }
+        game.current = vec![1, 1, 1];
+        game.current_y = 10;
+        let xs: Vec<usize> = placements(&game, &game.current, 3, 10)
+            .iter()
+            .map(|p| p.x)
+            .collect();
+        assert_eq!(xs, vec![2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_takes_match() {
+        // 2列目の上に置けば 4 が縦にそろう
+        let mut game = Game::with_seed(0, Rules::default());
+        game.field[15] = vec![1, 4, 2, 3, 1, 2];
+        game.field[14] = vec![0, 4, 0, 0, 0, 0];
+        game.current = vec![2, 3, 4];
+        game.next = vec![5, 6, 5];
+        let placement = best_placement(&game, &Weights::default()).unwrap();
+        assert_eq!(placement.x, 1);
+
+        let mut bot = AiBot::new(Weights::default());
+        while game.total_erased == 0 && game.frame < 100 {
+            game.update(bot.input(&game));
+        }
+        assert_eq!(game.total_erased, 3);
+    }
+
+    #[test]
+    fn test_plays_well() {
+        // 何もしないよりずっと長く生き残り、連鎖も出す
+        let idle = run_bot(
+            Game::with_seed(1, Rules::default()),
+            &mut crate::bot::IdleBot,
+            100_000,
+        );
+        let game = run_bot(
+            Game::with_seed(1, Rules::default()),
+            &mut AiBot::new(Weights::default()),
+            idle.frame * 3,
+        );
+        assert!(!game.is_over);
+        assert!(game.total_erased > 30);
+
+        let slow = run_bot(
+            Game::with_seed(1, Rules::default()),
+            &mut AiBot::new(Weights::default()).with_delay(4),
+            2_000,
+        );
+        assert!(slow.total_erased > 0);
+    }
+}
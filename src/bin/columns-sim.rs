@@ -0,0 +1,238 @@
+//! Headless batch simulator.
+//!
+//! Runs replays or bot games at full speed without SDL2 and prints their
+//! results, for regression-checking rule changes and comparing bots.
+
+use rust_columns::ai::{AiBot, Weights};
+use rust_columns::bot::{create_bot, Bot, BOT_NAMES};
+use rust_columns::desync::Desync;
+use rust_columns::sim::{run_bot, run_replay, Stats};
+use rust_columns::*;
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: columns-sim replay [--format csv|json] <FILE>...
+       columns-sim bot [OPTIONS]
+
+Replay mode runs each replay to the end, prints its result and fails if the
+result differs from the one recorded in the file or the game desyncs from
+the recorded checksums.
+
+Bot mode options:
+  --bot <NAME>          Bot to play with: idle, random or ai (default: random)
+  --weights <FILE>      Heuristic weights for the ai bot
+  --games <N>           Number of games (default: 100)
+  --seed <N>            Seed of the first game; game i uses seed + i (default: 0)
+  --rules <PRESET|FILE> Rule preset or rule file (default: classic)
+  --max-frames <N>      Stop a game after this many frames (default: 108000)
+  --format csv|json     Output format (default: csv)
+  --record-dir <DIR>    Save a replay of each game into DIR";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(|s| s.as_str()) {
+        Some("replay") => run_replays(&args[1..]),
+        Some("bot") => run_bots(&args[1..]),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        _ => Err("missing mode".to_string()),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn parse_format(value: &str) -> Result<Format, String> {
+    match value {
+        "csv" => Ok(Format::Csv),
+        "json" => Ok(Format::Json),
+        _ => Err(format!("unknown format: {}", value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} requires a value", name))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+fn print_stats(format: Format, rows: &[(String, Stats)], label: &str) {
+    match format {
+        Format::Csv => {
+            println!("{},{}", label, Stats::CSV_HEADER);
+            for (name, stats) in rows {
+                println!("{},{}", name, stats.to_csv());
+            }
+        }
+        Format::Json => {
+            let items: Vec<String> = rows
+                .iter()
+                .map(|(name, stats)| {
+                    format!(
+                        "{{\"{}\":{},{}",
+                        label,
+                        quote_json(name),
+                        &stats.to_json()[1..]
+                    )
+                })
+                .collect();
+            println!("[{}]", items.join(",\n "));
+        }
+    }
+}
+
+fn quote_json(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// 結果が記録と一致すれば true
+fn run_replays(args: &[String]) -> Result<bool, String> {
+    let mut format = Format::Csv;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = parse_format(args.next().ok_or("--format requires a value")?)?,
+            _ => files.push(arg.clone()),
+        }
+    }
+    if files.is_empty() {
+        return Err("no replay files".to_string());
+    }
+
+    let mut all_match = true;
+    let mut rows = Vec::new();
+    for file in files {
+        let replay = match Replay::load(Path::new(&file)).and_then(|r| r.validate().map(|_| r)) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("{}", e);
+                all_match = false;
+                continue;
+            }
+        };
+        let game = run_replay(&replay);
+        if let Some(desync) = Desync::find(&replay.checksums, &game.checksums) {
+            eprint!("{}: {}", file, desync.report());
+            all_match = false;
+        }
+        let stats = Stats::from_game(&game);
+        let recorded = &replay.result;
+        if (
+            stats.frames,
+            stats.score,
+            stats.total_erased,
+            stats.max_combo,
+            stats.max_erased_at_one_time,
+        ) != (
+            recorded.frames,
+            recorded.score,
+            recorded.total_erased,
+            recorded.max_combo,
+            recorded.max_erased_at_one_time,
+        ) {
+            eprintln!(
+                "{}: result differs from the recording: {:?}",
+                file, recorded
+            );
+            all_match = false;
+        }
+        rows.push((file, stats));
+    }
+    print_stats(format, &rows, "file");
+    Ok(all_match)
+}
+
+fn run_bots(args: &[String]) -> Result<bool, String> {
+    let mut bot_name = "random".to_string();
+    let mut games: u64 = 100;
+    let mut seed: u64 = 0;
+    let mut rules = Rules::default();
+    let mut max_frames: i32 = 60 * 60 * FPS;
+    let mut format = Format::Csv;
+    let mut record_dir = None;
+    let mut weights = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bot" => bot_name = args.next().ok_or("--bot requires a value")?.clone(),
+            "--games" => games = parse_number(arg, args.next())?,
+            "--seed" => seed = parse_number(arg, args.next())?,
+            "--rules" => {
+                rules = Rules::from_name_or_path(args.next().ok_or("--rules requires a value")?)?
+            }
+            "--max-frames" => max_frames = parse_number(arg, args.next())?,
+            "--format" => format = parse_format(args.next().ok_or("--format requires a value")?)?,
+            "--weights" => {
+                let path = args.next().ok_or("--weights requires a value")?;
+                weights = Some(Weights::load(Path::new(path))?);
+            }
+            "--record-dir" => {
+                record_dir = Some(Path::new(
+                    args.next().ok_or("--record-dir requires a value")?,
+                ))
+            }
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    if create_bot(&bot_name, 0).is_none() {
+        return Err(format!(
+            "unknown bot: {} (available: {})",
+            bot_name,
+            BOT_NAMES.join(", ")
+        ));
+    }
+    if weights.is_some() && bot_name != "ai" {
+        return Err("--weights only applies to the ai bot".to_string());
+    }
+
+    let mut rows = Vec::new();
+    for i in 0..games {
+        let seed = seed + i;
+        let mut bot: Box<dyn Bot> = match weights {
+            Some(weights) => Box::new(AiBot::new(weights)),
+            None => create_bot(&bot_name, seed).unwrap(),
+        };
+        let game = run_bot(
+            Game::with_seed(seed, rules.clone()),
+            bot.as_mut(),
+            max_frames,
+        );
+        if let Some(dir) = record_dir {
+            let path = dir.join(format!("{}-{}.replay", bot_name, seed));
+            Replay::from_game(&game, &bot_name).save(&path)?;
+        }
+        rows.push((bot_name.clone(), Stats::from_game(&game)));
+    }
+    print_stats(format, &rows, "bot");
+
+    if games > 0 {
+        let mean = |f: fn(&Stats) -> i32| {
+            rows.iter().map(|(_, s)| f(s) as f64).sum::<f64>() / games as f64
+        };
+        eprintln!(
+            "{} games: mean frames {:.1}, mean score {:.1}, mean total_erased {:.1}, mean max_combo {:.2}, mean max_erased_at_one_time {:.1}",
+            games,
+            mean(|s| s.frames),
+            mean(|s| s.score),
+            mean(|s| s.total_erased),
+            mean(|s| s.max_combo),
+            mean(|s| s.max_erased_at_one_time),
+        );
+    }
+    Ok(true)
+}
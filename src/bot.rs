@@ -0,0 +1,59 @@
+//! Computer players that drive `Game::update` without a human. The one that
+//! actually plays well lives in [`crate::ai`].
+
+use crate::ai::{AiBot, Weights};
+use crate::model::{Command, Game, Input};
+use rand::prelude::*;
+
+pub trait Bot {
+    /// 1フレーム分の入力を決める
+    fn input(&mut self, game: &Game) -> Input;
+}
+
+/// 何も操作しない
+pub struct IdleBot;
+
+impl Bot for IdleBot {
+    fn input(&mut self, _game: &Game) -> Input {
+        Input::NONE
+    }
+}
+
+/// ランダムに操作する
+pub struct RandomBot {
+    rng: StdRng,
+}
+
+impl RandomBot {
+    pub fn new(seed: u64) -> Self {
+        RandomBot {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Bot for RandomBot {
+    fn input(&mut self, _game: &Game) -> Input {
+        let commands = [
+            Command::None,
+            Command::Left,
+            Command::Right,
+            Command::Rotate,
+            Command::RotateReverse,
+            Command::SoftDrop,
+            Command::HardDrop,
+        ];
+        commands[self.rng.gen_range(0..commands.len())].into()
+    }
+}
+
+pub const BOT_NAMES: [&str; 3] = ["idle", "random", "ai"];
+
+pub fn create_bot(name: &str, seed: u64) -> Option<Box<dyn Bot>> {
+    match name {
+        "idle" => Some(Box::new(IdleBot)),
+        "random" => Some(Box::new(RandomBot::new(seed))),
+        "ai" => Some(Box::new(AiBot::new(Weights::default()))),
+        _ => None,
+    }
+}
@@ -0,0 +1,178 @@
+//! Command-line options of the game binary.
+
+use crate::input::{DEFAULT_ARR, DEFAULT_DAS};
+use crate::mode::Mode;
+use crate::model::FPS;
+use crate::puzzle::Stage;
+use crate::rules::Rules;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: rust-columns [OPTIONS]
+
+Options:
+  --seed <N>          Random seed (default: current time)
+  --replay <FILE>     Play back a replay file
+  --state <FILE>      Resume from a snapshot file
+  --mode <MODE>       Game mode: endless, pressure, sprint or ultra (default: endless)
+  --stage <ID|FILE>   Play a puzzle stage (01, 02, ...) or stage file
+  --record <FILE>     Where to save the replay of the game (default: command.log)
+  --no-record         Do not save a replay
+  --rules <PRESET|FILE>
+                      Rule preset (classic, wide, long, four, seven, magic) or rule file
+  --scale <N>         Window scale, 1 to 4 (default: 1)
+  --fps <N>           Frames per second (default: 30)
+  --das <N>           Frames before a held key starts repeating (default: 7)
+  --arr <N>           Frames between repeats of a held key (default: 2)
+  --mute              Disable sounds
+  --fullscreen        Start in fullscreen
+  --headless          Run without a window and print the result
+  -h, --help          Print this help";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub seed: Option<u64>,
+    pub replay: Option<PathBuf>,
+    pub state: Option<PathBuf>,
+    pub mode: Mode,
+    pub stage: Option<Stage>,
+    pub record: Option<PathBuf>,
+    pub rules: Rules,
+    pub scale: f32,
+    pub fps: i32,
+    pub das: i32,
+    pub arr: i32,
+    pub mute: bool,
+    pub fullscreen: bool,
+    pub headless: bool,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            seed: None,
+            replay: None,
+            state: None,
+            mode: Mode::Endless,
+            stage: None,
+            record: Some(PathBuf::from("command.log")),
+            rules: Rules::default(),
+            scale: 1.0,
+            fps: FPS,
+            das: DEFAULT_DAS,
+            arr: DEFAULT_ARR,
+            mute: false,
+            fullscreen: false,
+            headless: false,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    /// プログラム名を除いた引数を解析する
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        Options::parse_with(Options::default(), args)
+    }
+
+    /// 引数で指定されなかった値は defaults（設定ファイルの値など）のまま
+    pub fn parse_with<I: IntoIterator<Item = String>>(
+        defaults: Options,
+        args: I,
+    ) -> Result<Options, String> {
+        let mut options = defaults;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} requires a value", name));
+            match arg.as_str() {
+                "--seed" => options.seed = Some(parse_number(&arg, &value(&arg)?)?),
+                "--replay" => options.replay = Some(PathBuf::from(value(&arg)?)),
+                "--state" => options.state = Some(PathBuf::from(value(&arg)?)),
+                "--mode" => options.mode = value(&arg)?.parse()?,
+                "--stage" => {
+                    options.stage = Some(Stage::from_id_or_path(&value(&arg)?)?);
+                    options.mode = Mode::Puzzle;
+                }
+                "--record" => options.record = Some(PathBuf::from(value(&arg)?)),
+                "--no-record" => options.record = None,
+                "--rules" => options.rules = Rules::from_name_or_path(&value(&arg)?)?,
+                "--scale" => options.scale = parse_number(&arg, &value(&arg)?)?,
+                "--fps" => options.fps = parse_number(&arg, &value(&arg)?)?,
+                "--das" => options.das = parse_number(&arg, &value(&arg)?)?,
+                "--arr" => options.arr = parse_number(&arg, &value(&arg)?)?,
+                "--mute" => options.mute = true,
+                "--fullscreen" => options.fullscreen = true,
+                "--headless" => options.headless = true,
+                "-h" | "--help" => options.help = true,
+                _ => return Err(format!("unknown option: {}", arg)),
+            }
+        }
+
+        if options.seed.is_some() && options.replay.is_some() {
+            return Err("--seed cannot be used with --replay".to_string());
+        }
+        if options.state.is_some() && (options.seed.is_some() || options.replay.is_some()) {
+            return Err("--state cannot be used with --seed or --replay".to_string());
+        }
+        if options.mode == Mode::Puzzle && options.stage.is_none() {
+            return Err("--mode puzzle requires --stage".to_string());
+        }
+        if !(1.0..=4.0).contains(&options.scale) {
+            return Err(format!("--scale must be in 1..=4: {}", options.scale));
+        }
+        if !(1..=240).contains(&options.fps) {
+            return Err(format!("--fps must be in 1..=240: {}", options.fps));
+        }
+        if options.das < 1 || options.arr < 1 {
+            return Err("--das and --arr must be at least 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[]), Ok(Options::default()));
+
+        let options = parse(&[
+            "--seed", "42", "--rules", "wide", "--scale", "2", "--mute", "--mode", "pressure",
+        ])
+        .unwrap();
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(options.rules, Rules::preset("wide").unwrap());
+        assert_eq!(options.scale, 2.0);
+        assert!(options.mute);
+        assert_eq!(options.mode, Mode::Pressure);
+
+        assert!(parse(&["--seed"]).is_err());
+        assert!(parse(&["--seed", "x"]).is_err());
+        assert!(parse(&["--fps", "0"]).is_err());
+        assert!(parse(&["--seed", "1", "--replay", "a.replay"]).is_err());
+        assert!(parse(&["--state", "a.state", "--seed", "1"]).is_err());
+        assert!(parse(&["--mode", "marathon"]).is_err());
+        assert!(parse(&["--mode", "puzzle"]).is_err());
+        assert!(parse(&["--stage", "no-such-stage"]).is_err());
+        assert!(parse(&["--jump"]).is_err());
+
+        let defaults = Options {
+            fps: 60,
+            ..Options::default()
+        };
+        let options = Options::parse_with(defaults, ["--das".to_string(), "3".to_string()]);
+        assert_eq!(options.map(|o| (o.fps, o.das)), Ok((60, 3)));
+    }
+}
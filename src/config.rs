@@ -0,0 +1,111 @@
+//! Reader for the flat subset of TOML used by the text files of this crate
+//! (rule presets, settings, replays).
+//!
+//! Only `key = value` lines, `# comments` and `[section]` headers are
+//! supported. Keys inside a section are returned as `section.key`.
+
+use std::str::FromStr;
+
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    let mut section = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected `key = value`", i + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("line {}: missing key", i + 1));
+        }
+        let key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", section, key)
+        };
+        entries.push((key, unquote(value.trim())));
+    }
+    Ok(entries)
+}
+
+/// text を header だけの行で前後に分ける。値の中に同じ文字列があっても区切らない
+pub fn split_section<'a>(text: &'a str, header: &str) -> Option<(&'a str, &'a str)> {
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim() == header {
+            return Some((&text[..pos], &text[pos + line.len()..]));
+        }
+        pos += line.len();
+    }
+    None
+}
+
+pub fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for `{}`: {}", key, value))
+}
+
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => inner.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+// 文字列中の # はコメントとみなさない
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' if prev != '\\' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text =
+            "# comment\nfield_w = 8\nname = \"a # b\" # trailing\n\n[keys]\nleft = \"Left\"\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                ("field_w".to_string(), "8".to_string()),
+                ("name".to_string(), "a # b".to_string()),
+                ("keys.left".to_string(), "Left".to_string()),
+            ]
+        );
+        assert!(parse("field_w 8").is_err());
+    }
+
+    #[test]
+    fn test_split_section() {
+        let text = "name = \"[commands]\"\n[commands]\n1 None\n";
+        assert_eq!(
+            split_section(text, "[commands]"),
+            Some(("name = \"[commands]\"\n", "1 None\n"))
+        );
+        assert_eq!(split_section("[commands]", "[commands]"), Some(("", "")));
+        assert_eq!(split_section(text, "[checksums]"), None);
+    }
+}
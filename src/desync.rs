@@ -0,0 +1,256 @@
+//! Replay desync detection.
+//!
+//! A replay only holds the input, so any change to the rules or the random
+//! number generator makes old replays play out differently without notice.
+//! To catch that, the game records a [`Checksum`] every time the field
+//! settles (a piece lands or a chain ends): a hash of the field and the
+//! counters, plus the field itself so a mismatch can be shown as a diff.
+//! Replays store them one per line, `frame hash field`, with the rows of the
+//! field joined by `/` and one character per cell.
+//!
+//! ```text
+//! [checksums]
+//! 45 9f3a1c0e7b2d4410 000000/000000/.../000000/004000/001000/005000
+//! ```
+
+use crate::model::{Game, MAGIC};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// FNV-1a。依存を増やさず、実行環境によらず同じ値になる
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub frame: i32,
+    pub hash: u64,            // フィールド、ピース、カウンタ、乱数を引いた回数
+    pub field: Vec<Vec<i32>>, // 差分を出すための写し
+}
+
+impl Checksum {
+    pub fn of(game: &Game) -> Checksum {
+        let counters = [
+            game.score,
+            game.total_erased,
+            game.max_combo,
+            game.max_erased_at_one_time,
+            game.level,
+        ];
+        let values = game
+            .field
+            .iter()
+            .flatten()
+            .chain(&game.current)
+            .chain(&game.next)
+            .chain(&counters)
+            .flat_map(|v| v.to_le_bytes())
+            .chain(game.rng_draws.to_le_bytes());
+        Checksum {
+            frame: game.frame,
+            hash: fnv1a(values),
+            field: game.field.clone(),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let rows: Vec<String> = self
+            .field
+            .iter()
+            .map(|row| row.iter().map(|&v| cell_char(v)).collect())
+            .collect();
+        format!("{} {:016x} {}", self.frame, self.hash, rows.join("/"))
+    }
+
+    fn parse_line(line: &str) -> Option<Checksum> {
+        let mut parts = line.split_whitespace();
+        let frame = parts.next()?.parse().ok()?;
+        let hash = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let field = parts
+            .next()?
+            .split('/')
+            .map(|row| row.chars().map(cell_value).collect())
+            .collect::<Option<_>>()?;
+        parts
+            .next()
+            .is_none()
+            .then_some(Checksum { frame, hash, field })
+    }
+}
+
+fn cell_char(value: i32) -> char {
+    match value {
+        MAGIC => '*',
+        _ => char::from_digit(value as u32, 10).unwrap_or('?'),
+    }
+}
+
+fn cell_value(c: char) -> Option<i32> {
+    match c {
+        '*' => Some(MAGIC),
+        _ => c.to_digit(10).map(|v| v as i32),
+    }
+}
+
+pub(crate) fn encode_checksums(checksums: &[Checksum]) -> String {
+    checksums
+        .iter()
+        .map(|checksum| checksum.to_line() + "\n")
+        .collect()
+}
+
+pub(crate) fn decode_checksums(text: &str) -> Result<Vec<Checksum>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| Checksum::parse_line(line).ok_or(format!("line {}: bad checksum", i)))
+        .collect()
+}
+
+/// 記録と再生で最初に食い違ったところ。片方にしか無ければ None
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Desync {
+    pub index: usize, // 何回目の着地か
+    pub recorded: Option<Checksum>,
+    pub replayed: Option<Checksum>,
+}
+
+impl Desync {
+    /// 記録と再生を比べる。食い違いが無ければ None
+    pub fn find(recorded: &[Checksum], replayed: &[Checksum]) -> Option<Desync> {
+        let index = (0..recorded.len().max(replayed.len()))
+            .find(|&i| recorded.get(i) != replayed.get(i))?;
+        Some(Desync {
+            index,
+            recorded: recorded.get(index).cloned(),
+            replayed: replayed.get(index).cloned(),
+        })
+    }
+
+    /// 食い違いが分かったフレーム。着地のタイミングがずれたときは早い方
+    pub fn frame(&self) -> i32 {
+        [&self.recorded, &self.replayed]
+            .iter()
+            .filter_map(|checksum| checksum.as_ref().map(|c| c.frame))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// 端末に出す報告。フィールドは左が記録、右が再生で、違う行に * を付ける
+    pub fn report(&self) -> String {
+        let mut text = format!(
+            "replay desynced at frame {} (settle #{})\n",
+            self.frame(),
+            self.index + 1
+        );
+        let (recorded, replayed) = match (&self.recorded, &self.replayed) {
+            (Some(recorded), Some(replayed)) => (recorded, replayed),
+            (Some(recorded), None) => {
+                text += &format!(
+                    "recorded settle at frame {} never happened\n",
+                    recorded.frame
+                );
+                return text;
+            }
+            (None, Some(replayed)) => {
+                text += &format!("unexpected settle at frame {}\n", replayed.frame);
+                return text;
+            }
+            (None, None) => return text,
+        };
+        if recorded.frame != replayed.frame {
+            text += &format!(
+                "recorded settle at frame {}, replayed at frame {}\n",
+                recorded.frame, replayed.frame
+            );
+        }
+        if recorded.field == replayed.field {
+            text += "fields match; pieces, counters or random draws differ\n";
+            return text;
+        }
+        let rows = recorded.field.len().max(replayed.field.len());
+        let width = recorded
+            .field
+            .iter()
+            .map(|row| row.len())
+            .max()
+            .unwrap_or(0)
+            .max(8)
+            + 1;
+        text += &format!("    {:<width$} replayed\n", "recorded");
+        for y in 0..rows {
+            let row = |field: &Vec<Vec<i32>>| -> String {
+                field
+                    .get(y)
+                    .map(|row| row.iter().map(|&v| cell_char(v)).collect())
+                    .unwrap_or_default()
+            };
+            let (left, right) = (row(&recorded.field), row(&replayed.field));
+            let mark = if left == right { ' ' } else { '*' };
+            text += &format!("{}{:2} {:<width$} {}\n", mark, y, left, right);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::{Bot, RandomBot};
+    use crate::model::{Command, Input};
+    use crate::replay::Replay;
+    use crate::rules::Rules;
+
+    fn play() -> Game {
+        let mut game = Game::with_seed(9, Rules::preset("magic").unwrap());
+        let mut bot = RandomBot::new(9);
+        for _ in 0..900 {
+            let mut input = bot.input(&game);
+            input.remove(Command::HardDrop);
+            game.update(input);
+        }
+        game
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut game = play();
+        assert!(game.checksums.len() > 10);
+        game.checksums[3].field[15][0] = MAGIC;
+        let text = encode_checksums(&game.checksums);
+        assert_eq!(decode_checksums(&text), Ok(game.checksums));
+        assert!(decode_checksums("12 xyz 000/000").is_err());
+    }
+
+    #[test]
+    fn test_find_desync() {
+        let game = play();
+        let mut replay = Replay::from_game(&game, "");
+        assert_eq!(replay.find_desync(), None);
+
+        // 途中の入力を変えると、その後の最初の着地でずれが見つかる
+        replay.commands[300] = Input::from(Command::Left);
+        let desync = replay.find_desync().unwrap();
+        assert!(desync.frame() >= 300);
+        let first = replay
+            .checksums
+            .iter()
+            .position(|c| c.frame >= 300)
+            .unwrap();
+        assert_eq!(desync.index, first);
+        assert!(desync.report().contains("replay desynced at frame"));
+        assert!(desync.report().contains("\n*"));
+
+        // 着地が足りないときも報告する
+        let mut replay = Replay::from_game(&game, "");
+        let last = replay.checksums.last().unwrap().frame as usize;
+        replay.commands.truncate(last);
+        let desync = replay.find_desync().unwrap();
+        assert_eq!(desync.replayed, None);
+        assert!(desync.report().contains("never happened"));
+    }
+}
@@ -0,0 +1,346 @@
+//! Local high-score tables.
+//!
+//! There is one table per game mode and rule preset (the key, such as
+//! `endless-classic`), stored as `highscores/<key>.toml` in the data
+//! directory. Every entry keeps a copy of its replay under `replays/` so it
+//! can be watched later. Sprint tables are ranked by the clear time (`time`,
+//! in frames) instead of the score, fastest first.
+//!
+//! ```text
+//! [1]
+//! name = "aoyama"
+//! score = 1230
+//! jewels = 52
+//! max_combo = 3
+//! date = 1706226400
+//! seed = 1706226338
+//! time = 0
+//! replay = "endless-classic-1706226400-1706226338.replay"
+//! ```
+
+use crate::config;
+use crate::mode::Mode;
+use crate::model::Game;
+use crate::replay::Replay;
+use std::path::{Path, PathBuf};
+
+pub const MAX_ENTRIES: usize = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub score: i32,
+    pub jewels: i32,
+    pub max_combo: i32,
+    pub date: u64, // UNIX時間（秒）
+    pub seed: u64,
+    pub time: i32,      // スプリントのクリアタイム（フレーム）
+    pub replay: String, // replays ディレクトリ内のファイル名
+}
+
+impl Entry {
+    /// 表と比べるための結果だけのエントリ
+    pub fn from_game(game: &Game) -> Entry {
+        Entry {
+            score: game.score,
+            jewels: game.total_erased,
+            max_combo: game.max_combo,
+            seed: game.seed,
+            time: game.clear_time(),
+            ..Default::default()
+        }
+    }
+}
+
+/// 表の並べ方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ranking {
+    #[default]
+    Score, // 得点の高い順
+    Time, // クリアタイムの短い順
+}
+
+impl Ranking {
+    pub fn of(mode: Mode) -> Ranking {
+        match mode {
+            Mode::Sprint => Ranking::Time,
+            _ => Ranking::Score,
+        }
+    }
+
+    /// 表に載せられる結果か
+    fn qualifies(self, entry: &Entry) -> bool {
+        match self {
+            Ranking::Score => entry.score > 0,
+            Ranking::Time => entry.time > 0,
+        }
+    }
+
+    /// a が b と同じかそれより上
+    fn at_least(self, a: &Entry, b: &Entry) -> bool {
+        match self {
+            Ranking::Score => a.score >= b.score,
+            Ranking::Time => a.time <= b.time,
+        }
+    }
+}
+
+/// ranking の順に並んだ表
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Table {
+    pub ranking: Ranking,
+    pub entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn new(ranking: Ranking) -> Table {
+        Table {
+            ranking,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str, ranking: Ranking) -> Result<Table, String> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for (key, value) in config::parse(text)? {
+            let Some((rank, key)) = key.split_once('.') else {
+                return Err(format!("entry outside of a section: {}", key));
+            };
+            let rank: usize = config::parse_value("rank", rank)?;
+            if rank == 0 || rank > MAX_ENTRIES {
+                return Err(format!("bad rank: {}", rank));
+            }
+            if entries.len() < rank {
+                entries.resize(rank, Entry::default());
+            }
+            let entry = &mut entries[rank - 1];
+            match key {
+                "name" => entry.name = value,
+                "score" => entry.score = config::parse_value(key, &value)?,
+                "jewels" => entry.jewels = config::parse_value(key, &value)?,
+                "max_combo" => entry.max_combo = config::parse_value(key, &value)?,
+                "date" => entry.date = config::parse_value(key, &value)?,
+                "seed" => entry.seed = config::parse_value(key, &value)?,
+                "time" => entry.time = config::parse_value(key, &value)?,
+                "replay" => entry.replay = value,
+                _ => return Err(format!("unknown key: {}", key)),
+            }
+        }
+        // タイムで並べる表には完走した記録しか載らない
+        if let Some(i) = entries
+            .iter()
+            .position(|e| ranking == Ranking::Time && e.time <= 0)
+        {
+            return Err(format!("entry {} has no time", i + 1));
+        }
+        match ranking {
+            Ranking::Score => entries.sort_by_key(|e| std::cmp::Reverse(e.score)),
+            Ranking::Time => entries.sort_by_key(|e| e.time),
+        }
+        Ok(Table { ranking, entries })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                text += "\n";
+            }
+            text += &format!(
+                "[{}]\nname = {}\nscore = {}\njewels = {}\nmax_combo = {}\ndate = {}\nseed = {}\ntime = {}\nreplay = {}\n",
+                i + 1,
+                config::quote(&entry.name),
+                entry.score,
+                entry.jewels,
+                entry.max_combo,
+                entry.date,
+                entry.seed,
+                entry.time,
+                config::quote(&entry.replay),
+            );
+        }
+        text
+    }
+
+    /// この結果で表に入るときの順位（0始まり）。同点なら先に入った方が上
+    pub fn rank(&self, entry: &Entry) -> Option<usize> {
+        if !self.ranking.qualifies(entry) {
+            return None;
+        }
+        let rank = self
+            .entries
+            .iter()
+            .filter(|e| self.ranking.at_least(e, entry))
+            .count();
+        (rank < MAX_ENTRIES).then_some(rank)
+    }
+
+    /// 1位を更新する結果か
+    pub fn is_best(&self, entry: &Entry) -> bool {
+        self.rank(entry) == Some(0)
+    }
+
+    /// 入れた順位と、表からあふれたエントリを返す
+    pub fn insert(&mut self, entry: Entry) -> (Option<usize>, Vec<Entry>) {
+        let Some(rank) = self.rank(&entry) else {
+            return (None, Vec::new());
+        };
+        self.entries.insert(rank, entry);
+        let dropped = self.entries.split_off(self.entries.len().min(MAX_ENTRIES));
+        (Some(rank), dropped)
+    }
+}
+
+/// データディレクトリ内のハイスコアとリプレイ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Store {
+    pub dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: &Path) -> Store {
+        Store {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn table_path(&self, key: &str) -> PathBuf {
+        self.dir.join("highscores").join(format!("{}.toml", key))
+    }
+
+    pub fn replay_path(&self, entry: &Entry) -> PathBuf {
+        self.dir.join("replays").join(&entry.replay)
+    }
+
+    /// まだファイルが無ければ空の表
+    pub fn load(&self, key: &str, ranking: Ranking) -> Result<Table, String> {
+        let path = self.table_path(key);
+        if !path.exists() {
+            return Ok(Table::new(ranking));
+        }
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Table::parse(&text, ranking).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// 表に入る結果ならリプレイを保存して登録し、順位を返す
+    pub fn add(&self, key: &str, replay: &Replay) -> Result<Option<usize>, String> {
+        let mut table = self.load(key, Ranking::of(replay.mode))?;
+        let entry = Entry {
+            name: replay.player.clone(),
+            score: replay.result.score,
+            jewels: replay.result.total_erased,
+            max_combo: replay.result.max_combo,
+            date: replay.date,
+            seed: replay.seed,
+            time: replay.result.time,
+            replay: format!("{}-{}-{}.replay", key, replay.date, replay.seed),
+        };
+        if table.rank(&entry).is_none() {
+            return Ok(None);
+        }
+
+        for dir in ["highscores", "replays"] {
+            let dir = self.dir.join(dir);
+            std::fs::create_dir_all(&dir)
+                .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        }
+        replay.save(&self.replay_path(&entry))?;
+        let (rank, dropped) = table.insert(entry);
+        let path = self.table_path(key);
+        std::fs::write(&path, table.to_text())
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        // あふれたエントリのリプレイは消す
+        for entry in dropped {
+            let _ = std::fs::remove_file(self.replay_path(&entry));
+        }
+        Ok(rank)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::Rules;
+
+    fn entry(score: i32) -> Entry {
+        Entry {
+            name: format!("p{}", score),
+            score,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_table() {
+        let mut table = Table::default();
+        assert_eq!(table.rank(&entry(0)), None);
+        for score in 1..=12 {
+            table.insert(entry(score * 10));
+        }
+        assert_eq!(table.entries.len(), MAX_ENTRIES);
+        assert!(table.is_best(&entry(130)));
+        assert!(!table.is_best(&entry(120)));
+        assert_eq!(table.rank(&entry(30)), None);
+        assert_eq!(table.rank(&entry(40)), Some(9));
+
+        let (rank, dropped) = table.insert(entry(55));
+        assert_eq!(rank, Some(7));
+        assert_eq!(dropped, vec![entry(30)]);
+
+        assert_eq!(Table::parse(&table.to_text(), Ranking::Score), Ok(table));
+        assert!(Table::parse("name = \"a\"", Ranking::Score).is_err());
+        assert!(Table::parse("[0]\nscore = 1", Ranking::Score).is_err());
+    }
+
+    #[test]
+    fn test_time_table() {
+        let time = |time: i32| Entry {
+            time,
+            score: 1000 - time,
+            ..Default::default()
+        };
+        let mut table = Table::new(Ranking::Time);
+        // 完走していなければ得点があっても載らない
+        assert_eq!(
+            table.rank(&Entry {
+                score: 50,
+                ..Default::default()
+            }),
+            None
+        );
+        for t in [300, 100, 200] {
+            table.insert(time(t));
+        }
+        assert_eq!(table.entries, vec![time(100), time(200), time(300)]);
+        assert!(table.is_best(&time(99)));
+        assert_eq!(table.rank(&time(200)), Some(2));
+
+        let text = table.to_text().replace("time = 100", "time = 400");
+        let parsed = Table::parse(&text, Ranking::Time).unwrap();
+        assert_eq!(parsed.entries[2].time, 400);
+        let text = table.to_text().replace("time = 200\n", "");
+        assert!(Table::parse(&text, Ranking::Time).is_err());
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir().join(format!("rust-columns-test-{}", std::process::id()));
+        let store = Store::new(&dir);
+        let mut replay = Replay::from_game(&Game::with_seed(7, Rules::default()), "tester");
+        assert_eq!(store.add("endless-classic", &replay), Ok(None));
+
+        replay.result.score = 100;
+        assert_eq!(store.add("endless-classic", &replay), Ok(Some(0)));
+        let table = store.load("endless-classic", Ranking::Score).unwrap();
+        assert_eq!(table.entries[0].name, "tester");
+        let saved = Replay::load(&store.replay_path(&table.entries[0])).unwrap();
+        assert_eq!(saved, replay);
+        assert_eq!(
+            store.load("endless-wide", Ranking::Score),
+            Ok(Table::default())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
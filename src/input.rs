@@ -0,0 +1,142 @@
+//! Turning held buttons into per-frame inputs.
+//!
+//! Auto-repeat of held keys is done here, counted in game frames, instead of
+//! relying on the key repeat of the OS. The inputs it produces are what
+//! `Game::update` records, so replays do not depend on the machine.
+
+use crate::model::{Command, Input, FPS};
+
+pub const DEFAULT_DAS: i32 = FPS / 4; // 押し始めてからリピートが始まるまでのフレーム数
+pub const DEFAULT_ARR: i32 = 2; // リピートの間隔（フレーム数）
+
+/// そのフレームに押されているボタン
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub down: bool,
+    pub rotate: bool,
+    pub rotate_reverse: bool,
+    pub hard_drop: bool,
+}
+
+impl Buttons {
+    /// そのコマンドを出すボタンを押したことにする。Pause などボタンが無いものは無視する
+    pub fn press(&mut self, command: Command) {
+        match command {
+            Command::Left => self.left = true,
+            Command::Right => self.right = true,
+            Command::SoftDrop => self.down = true,
+            Command::Rotate => self.rotate = true,
+            Command::RotateReverse => self.rotate_reverse = true,
+            Command::HardDrop => self.hard_drop = true,
+            Command::None | Command::Pause | Command::Step => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputState {
+    pub das: i32,          // delayed auto shift
+    pub arr: i32,          // auto repeat rate
+    held_frames: [i32; 6], // Buttons の並び順で、押し続けているフレーム数
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        InputState::new(DEFAULT_DAS, DEFAULT_ARR)
+    }
+}
+
+impl InputState {
+    pub fn new(das: i32, arr: i32) -> Self {
+        InputState {
+            das: das.max(1),
+            arr: arr.max(1),
+            held_frames: [0; 6],
+        }
+    }
+
+    /// 1フレームに1回呼び、そのフレームの入力を返す。
+    /// 回転とハードドロップは押した瞬間だけ、左右は押した瞬間と das フレーム後から arr フレームごと、
+    /// 下は押している間ずっと。左右が同時なら打ち消し合う
+    pub fn update(&mut self, buttons: Buttons) -> Input {
+        let pressed = [
+            buttons.left,
+            buttons.right,
+            buttons.down,
+            buttons.rotate,
+            buttons.rotate_reverse,
+            buttons.hard_drop,
+        ];
+        for (frames, pressed) in self.held_frames.iter_mut().zip(pressed) {
+            *frames = if pressed { *frames + 1 } else { 0 };
+        }
+        let [left, right, down, rotate, rotate_reverse, hard_drop] = self.held_frames;
+
+        let mut input = Input::NONE;
+        if rotate == 1 {
+            input.insert(Command::Rotate);
+        }
+        if rotate_reverse == 1 {
+            input.insert(Command::RotateReverse);
+        }
+        if self.is_repeat(left) && !self.is_repeat(right) {
+            input.insert(Command::Left);
+        }
+        if self.is_repeat(right) && !self.is_repeat(left) {
+            input.insert(Command::Right);
+        }
+        if down > 0 {
+            input.insert(Command::SoftDrop);
+        }
+        if hard_drop == 1 {
+            input.insert(Command::HardDrop);
+        }
+        input
+    }
+
+    fn is_repeat(&self, frames: i32) -> bool {
+        frames == 1 || (frames > self.das && (frames - 1 - self.das) % self.arr == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_repeat() {
+        let mut input = InputState::new(4, 2);
+        let left = Buttons {
+            left: true,
+            ..Default::default()
+        };
+        let inputs: Vec<Input> = (0..10).map(|_| input.update(left)).collect();
+        let (l, n) = (Input::from(Command::Left), Input::NONE);
+        assert_eq!(inputs, [l, n, n, n, l, n, l, n, l, n]);
+
+        assert_eq!(input.update(Buttons::default()), n);
+        assert_eq!(input.update(left), l);
+    }
+
+    #[test]
+    fn test_simultaneous() {
+        let mut input = InputState::default();
+        let buttons = Buttons {
+            left: true,
+            down: true,
+            rotate: true,
+            ..Default::default()
+        };
+        assert_eq!(input.update(buttons).to_string(), "Rotate+Left+SoftDrop");
+        assert_eq!(input.update(buttons).to_string(), "SoftDrop");
+
+        let both = Buttons {
+            left: true,
+            right: true,
+            ..Default::default()
+        };
+        assert_eq!(InputState::default().update(both), Input::NONE);
+    }
+}
@@ -0,0 +1,29 @@
+//! Headless game rules of rust-columns.
+//!
+//! This crate does not depend on SDL2. The SDL2 front end lives in `main.rs`
+//! and is built only with the `sdl` feature.
+
+pub mod ai;
+pub mod bot;
+pub mod cli;
+pub mod config;
+pub mod desync;
+pub mod highscore;
+pub mod input;
+pub mod mode;
+pub mod model;
+pub mod paths;
+pub mod puzzle;
+pub mod replay;
+pub mod rules;
+pub mod scene;
+pub mod score;
+pub mod settings;
+pub mod sim;
+pub mod snapshot;
+pub mod versus;
+pub mod viewer;
+
+pub use crate::model::*;
+pub use crate::replay::*;
+pub use crate::rules::*;
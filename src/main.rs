@@ -1,21 +1,45 @@
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use rust_columns::ai::{AiBot, Weights};
+use rust_columns::bot::Bot;
+use rust_columns::highscore::{Entry, Ranking, Store, Table};
+use rust_columns::input::{Buttons, InputState};
+use rust_columns::mode::{Mode, SPRINT_JEWELS};
+use rust_columns::puzzle::{self, Goal, Records, Stage};
+use rust_columns::scene::*;
+use rust_columns::settings::{self, Bindings, Settings};
+use rust_columns::versus::{Outcome, Versus, PLAYERS};
+use rust_columns::viewer::Viewer;
+use rust_columns::*;
+use sdl2::controller::{Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Scancode};
 use sdl2::mixer;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
 use sdl2::ttf::Sdl2TtfContext;
-use sdl2::video::{Window, WindowContext};
+use sdl2::video::{FullscreenType, Window, WindowContext};
 use std::collections::HashMap;
 use std::fs;
-use std::time::{Duration, SystemTime};
-mod model;
-use crate::model::*;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub const WINDOW_TITLE: &str = "rust-columns";
-pub const SCREEN_WIDTH: i32 = FIELD_W as i32 * CELL_SIZE + INFO_WIDTH;
-pub const SCREEN_HEIGHT: i32 = (FIELD_H - INVISIBLE_ROW_COUNT) as i32 * CELL_SIZE;
 pub const INFO_WIDTH: i32 = 190;
+pub const REPLAY_SEEK_SECONDS: i32 = 5; // 左右キーで飛ぶ秒数
+/// ハイスコア画面で見られるモード。パズルはステージ選択にクリアタイムを出す
+const HIGHSCORE_MODES: [Mode; 4] = [Mode::Endless, Mode::Pressure, Mode::Sprint, Mode::Ultra];
+/// タイトルでこれだけ何も押されなければデモを始める
+const DEMO_WAIT: i32 = FPS * 20;
+/// 対戦の AI が操作の間に空けるフレーム数
+const VERSUS_AI_DELAY: i32 = 6;
+
+fn screen_width(rules: &Rules) -> i32 {
+    rules.field_w as i32 * CELL_SIZE + INFO_WIDTH
+}
+
+fn screen_height(rules: &Rules) -> i32 {
+    (rules.field_h - INVISIBLE_ROW_COUNT) as i32 * CELL_SIZE
+}
 
 struct Image<'a> {
     texture: Texture<'a>,
@@ -43,93 +67,1116 @@ struct Resources<'a> {
 }
 
 pub fn main() -> Result<(), String> {
+    // 読めない設定ファイルは上書きしないよう、保存先を無くして既定値で続ける
+    let mut settings_path = settings::default_path();
+    let mut settings = match settings_path.as_deref().map(Settings::load) {
+        Some(Ok(settings)) => settings,
+        Some(Err(e)) => {
+            eprintln!("{} (using default settings)", e);
+            settings_path = None;
+            Settings::default()
+        }
+        None => Settings::default(),
+    };
+    let mut options =
+        match cli::Options::parse_with(settings.to_options(), std::env::args().skip(1)) {
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("{}\n\n{}", e, cli::USAGE);
+                std::process::exit(2);
+            }
+        };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    if options.headless {
+        return run_headless(&options);
+    }
+    // コマンドラインの値はこの回だけ。保存するのはファイルの値にオプション画面での変更を足したもの
+    let mut saved_settings = settings.clone();
+    settings.apply_options(&options);
+    let (mut input_map, mut input_map2) = match InputMap::for_players(&settings) {
+        Ok(input_maps) => input_maps,
+        Err(e) => {
+            eprintln!("{} (using default keys and buttons)", e);
+            settings.keys = Bindings::default_keys();
+            settings.keys2 = Bindings::default_keys2();
+            settings.buttons = Bindings::default_buttons();
+            InputMap::for_players(&settings)?
+        }
+    };
+
     let sdl_context = sdl2::init()?;
 
     let video_subsystem = sdl_context.video()?;
-    let window = video_subsystem
-        .window(WINDOW_TITLE, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
-        .position_centered()
-        .opengl()
-        .build()
-        .map_err(|e| e.to_string())?;
+    // 接続済みのコントローラーも起動時に ControllerDeviceAdded で届く
+    let controller_subsystem = sdl_context.game_controller()?;
+    let mut controllers: Vec<GameController> = Vec::new();
+    let width = screen_width(&options.rules);
+    let height = screen_height(&options.rules);
+    let mut window_builder = video_subsystem.window(
+        WINDOW_TITLE,
+        (width as f32 * settings.scale) as u32,
+        (height as f32 * settings.scale) as u32,
+    );
+    window_builder.position_centered().opengl();
+    if settings.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+    let window = window_builder.build().map_err(|e| e.to_string())?;
 
     sdl_context.mouse().show_cursor(false);
 
-    init_mixer();
+    if !options.mute {
+        init_mixer();
+    }
 
     let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
 
     let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
     canvas.set_blend_mode(BlendMode::Blend);
+    // 拡大・フルスクリーン時も論理サイズで描画する
+    canvas
+        .set_logical_size(width as u32, height as u32)
+        .map_err(|e| e.to_string())?;
 
     let texture_creator = canvas.texture_creator();
     let mut resources = load_resources(&texture_creator, &mut canvas, &ttf_context);
 
     let mut event_pump = sdl_context.event_pump()?;
 
-    let mut game = Game::new();
+    let mut input = InputState::new(settings.das, settings.arr);
+    let mut input2 = InputState::new(settings.das, settings.arr); // 対戦の2人目
+    set_volume(&settings, &options);
+    let mut settings_before = settings.clone(); // オプション画面に入ったときの設定
 
-    println!("Keys:");
-    println!("  Left    : Move left");
-    println!("  Right   : Move right");
-    println!("  Down    : Drop");
-    println!("  Space   : Rotate");
-    println!("  Enter   : Restart when gameover");
+    // リプレイやスナップショットを指定されたときはタイトルを飛ばしてすぐ始める
+    let mut scene = Scene::Title;
+    let mut game = Game::default();
+    // 見ているリプレイと再生の状態、見終わったら戻る画面
+    let mut watching = None;
+    let mut viewer = None;
+    let mut watched_from = Scene::Title;
+    if let Some(path) = options.replay.take() {
+        let replay = Replay::load(&path)?;
+        replay.validate()?;
+        println!("replay loaded: seed = {}", replay.seed);
+        viewer = Some(open_viewer(&replay));
+        fit_window(&mut canvas, &replay.rules, &settings, 1)?;
+        watching = Some(replay);
+        scene = Scene::Replay;
+    } else if options.state.is_some() {
+        game = start_game(&mut canvas, &options, &settings)?;
+        scene = Scene::Playing;
+    }
+    let suspend_path = snapshot::suspend_path();
+    let mut can_continue = suspend_path.as_ref().is_some_and(|path| path.exists());
+    let mut title_menu = Menu::new(title_items(can_continue).len());
+    let mut options_menu = Menu::new(OPTION_ITEMS.len());
+    let mut keys_menu = Menu::new(Bindings::ACTIONS.len() + 1);
+    let mut rebinding = None; // キーの入力待ちをしている操作
+    let mut summary = None;
+    let store = paths::data_dir().map(|dir| Store::new(&dir));
+    let mut highscore_key_of_game = String::new();
+    let mut name_entry = NameEntry::default();
+    // ハイスコア画面で見ているモードとルール（viewer_table_key）と、そこから再生したリプレイ
+    let mut viewer_table_index = 0;
+    let mut viewer_menu = Menu::new(1);
+    let mut viewer_table = Table::default();
+    let mut versus = None;
+    let mut versus_ai: Option<AiBot> = None; // VS COMPUTER の2人目
+    let mut demo_bot = AiBot::new(Weights::default());
+    let mut title_idle = 0;
+    // パズルのステージ一覧と、ステージごとのいちばん速いクリアタイム
+    let mut stages: Vec<Stage> = Vec::new();
+    let mut stage_menu = Menu::new(1);
+    let mut records = Records::default();
+
+    println!("Keys (buttons):");
+    for action in Bindings::ACTIONS {
+        println!(
+            "  {:<14}: {} ({})",
+            action,
+            settings.keys.to_value(action),
+            settings.buttons.to_value(action)
+        );
+    }
+    println!("  {:<14}: Escape", "back to title");
+    println!("Versus player 2 (second controller):");
+    for action in &Bindings::ACTIONS[..6] {
+        println!("  {:<14}: {}", action, settings.keys2.to_value(action));
+    }
+    println!("  {:<14}: F2", "save snapshot");
+    println!("Replay: Up/Down speed, Left/Right seek, Comma step back, PageUp/PageDown chains, Home restart");
 
     'running: loop {
         let started = SystemTime::now();
 
-        let mut command = Command::None;
-        let mut is_keydown = false;
+        let mut keys_down = Vec::new(); // このフレームに押されたキー（すぐ離されたものも含む）
+        let mut buttons_down = Vec::new(); // ゲームパッドのボタンも同じ
+        let mut controller_buttons_down = Vec::new(); // 対戦用に、どのコントローラーか
+        let mut focus_lost = false;
+        let mut typed = String::new();
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => break 'running,
+                Event::Window {
+                    win_event: WindowEvent::FocusLost,
+                    ..
+                } => focus_lost = true,
+                Event::TextInput { text, .. } => typed += &text,
                 Event::KeyDown {
                     keycode: Some(code),
                     ..
-                } => {
-                    is_keydown = true;
-                    if code == Keycode::Escape {
-                        break 'running;
+                } => keys_down.push(code),
+                Event::ControllerButtonDown { which, button, .. } => {
+                    buttons_down.push(button);
+                    controller_buttons_down.push((which, button));
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("controller connected: {}", controller.name());
+                            controllers.push(controller);
+                        }
+                        Err(e) => println!("cannot open controller {}: {}", which, e),
                     }
-                    match code {
-                        Keycode::Return => {
-                            if game.is_over {
-                                game = Game::new();
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    controllers.retain(|controller| controller.instance_id() != which);
+                }
+                _ => {}
+            }
+        }
+        let menu_keys = menu_keys(&keys_down, &buttons_down);
+
+        match scene {
+            Scene::Title => {
+                let items = title_items(can_continue);
+                title_menu.resize(items.len());
+                if keys_down.is_empty() && buttons_down.is_empty() {
+                    title_idle += 1;
+                } else {
+                    title_idle = 0;
+                }
+                if title_idle >= DEMO_WAIT {
+                    title_idle = 0;
+                    game = Game::new(settings.rules.clone());
+                    demo_bot = AiBot::new(Weights::default());
+                    fit_window(&mut canvas, &game.rules, &settings, 1)?;
+                    scene = Scene::Demo;
+                }
+                for &key in &menu_keys {
+                    title_menu.move_cursor(key);
+                    match (key, items[title_menu.cursor]) {
+                        (MenuKey::Back, _) | (MenuKey::Select, TitleItem::Quit) => {
+                            break 'running;
+                        }
+                        (MenuKey::Select, TitleItem::Continue) => {
+                            let Some(path) = &suspend_path else {
+                                continue;
+                            };
+                            // 続きは一度だけ。読めなかったファイルも消す
+                            let loaded = Game::load_snapshot(path);
+                            let _ = fs::remove_file(path);
+                            can_continue = false;
+                            match loaded {
+                                Ok(loaded) => {
+                                    game = loaded;
+                                    fit_window(&mut canvas, &game.rules, &settings, 1)?;
+                                    scene = Scene::Playing;
+                                }
+                                Err(e) => println!("{}", e),
                             }
+                            break;
+                        }
+                        (
+                            MenuKey::Select,
+                            item @ (TitleItem::Endless
+                            | TitleItem::Pressure
+                            | TitleItem::Sprint
+                            | TitleItem::Ultra),
+                        ) => {
+                            options.rules = settings.rules.clone();
+                            options.state = None;
+                            options.stage = None;
+                            options.mode = match item {
+                                TitleItem::Pressure => Mode::Pressure,
+                                TitleItem::Sprint => Mode::Sprint,
+                                TitleItem::Ultra => Mode::Ultra,
+                                _ => Mode::Endless,
+                            };
+                            game = start_game(&mut canvas, &options, &settings)?;
+                            scene = Scene::Playing;
+                        }
+                        (MenuKey::Select, TitleItem::Puzzle) => {
+                            match puzzle::load_stages(Path::new(puzzle::STAGE_DIR)) {
+                                Ok(loaded) if !loaded.is_empty() => {
+                                    stages = loaded;
+                                    stage_menu.resize(stages.len());
+                                    records = load_records(&store);
+                                    scene = Scene::Stages;
+                                }
+                                Ok(_) => println!("no stages in {}", puzzle::STAGE_DIR),
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        (MenuKey::Select, item @ (TitleItem::Versus | TitleItem::VersusAi)) => {
+                            let new_versus = Versus::new(time_seed(), settings.rules.clone());
+                            println!("random seed = {}", new_versus.seed);
+                            fit_window(&mut canvas, &settings.rules, &settings, PLAYERS)?;
+                            versus = Some(new_versus);
+                            versus_ai = (item == TitleItem::VersusAi).then(new_versus_ai);
+                            scene = Scene::Versus;
                         }
-                        Keycode::F1 => {
-                            game.toggle_debug();
-                            println!("{:?}", game);
+                        (MenuKey::Select, TitleItem::HighScores) => {
+                            viewer_table_index = Rules::PRESET_NAMES
+                                .iter()
+                                .position(|&name| name == settings.rules_name())
+                                .unwrap_or(0);
+                            viewer_table = load_table(&store, viewer_table_index);
+                            viewer_menu = Menu::new(viewer_table.entries.len().max(1));
+                            scene = Scene::HighScores;
                         }
-                        Keycode::Left => command = Command::Left,
-                        Keycode::Right => command = Command::Right,
-                        Keycode::Down => command = Command::Down,
-                        Keycode::Space => command = Command::Rotate,
+                        (MenuKey::Select, TitleItem::Options) => {
+                            settings_before = settings.clone();
+                            scene = Scene::Options;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Scene::Options => {
+                for &key in &menu_keys {
+                    options_menu.move_cursor(key);
+                    let item = OPTION_ITEMS[options_menu.cursor];
+                    match key {
+                        MenuKey::Left => settings.change(item, -1),
+                        MenuKey::Right => settings.change(item, 1),
+                        MenuKey::Select if item == OptionItem::Fullscreen => {
+                            settings.change(item, 1)
+                        }
+                        MenuKey::Select if item == OptionItem::Keys => {
+                            keys_menu = Menu::new(Bindings::ACTIONS.len() + 1);
+                            scene = Scene::Keys;
+                        }
+                        MenuKey::Back => scene = Scene::Title,
+                        MenuKey::Select if item == OptionItem::Back => scene = Scene::Title,
                         _ => {}
+                    }
+                }
+                // 変更はすぐに反映し、画面を出るときに保存する
+                set_volume(&settings, &options);
+                if (settings.scale, settings.fullscreen)
+                    != (settings_before.scale, settings_before.fullscreen)
+                {
+                    fit_window(&mut canvas, &settings.rules, &settings, 1)?;
+                }
+                if scene != Scene::Options && settings != settings_before {
+                    saved_settings.merge_changes(&settings_before, &settings);
+                    if let Some(path) = &settings_path {
+                        if let Err(e) = saved_settings.save(path) {
+                            println!("{}", e);
+                        }
+                    }
+                }
+            }
+            Scene::Keys => {
+                if let Some(action) = rebinding {
+                    // 次に押したキーかボタンを割り当てる。Escape で取り消し
+                    if keys_down.contains(&Keycode::Escape) {
+                        rebinding = None;
+                    } else if let Some(code) = keys_down.first() {
+                        if let Err(e) = settings.assign_key(action, &code.name()) {
+                            println!("{}", e);
+                        }
+                        rebinding = None;
+                    } else if let Some(button) = buttons_down.first() {
+                        if let Err(e) = settings.buttons.assign(action, &button.string()) {
+                            println!("{}", e);
+                        }
+                        rebinding = None;
+                    }
+                    // 使えない割り当てになったら前の割り当てのまま続ける
+                    if rebinding.is_none() {
+                        match InputMap::for_players(&settings) {
+                            Ok(maps) => (input_map, input_map2) = maps,
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                } else {
+                    for &key in &menu_keys {
+                        keys_menu.move_cursor(key);
+                        let action = Bindings::ACTIONS.get(keys_menu.cursor);
+                        match (key, action) {
+                            (MenuKey::Select, Some(&action)) => {
+                                rebinding = Some(action);
+                                break;
+                            }
+                            (MenuKey::Select, None) | (MenuKey::Back, _) => {
+                                scene = Scene::Options;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Scene::Playing => {
+                if keys_down.contains(&Keycode::Escape) {
+                    if !game.is_over {
+                        save_replay(&game, &options);
+                        // 遊んでいたゲームはタイトルから続けられるようにする
+                        if let (Some(path), false) = (&suspend_path, game.replay_loaded) {
+                            match game.save_snapshot(path) {
+                                Ok(()) => can_continue = true,
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                    }
+                    scene = Scene::Title;
+                } else {
+                    if keys_down.contains(&Keycode::F1) {
+                        game.toggle_debug();
+                        println!("{:?}", game);
+                    }
+                    if keys_down.contains(&Keycode::F2) {
+                        if let Some(path) = snapshot::report_path(&game) {
+                            match game.save_snapshot(&path) {
+                                Ok(()) => println!("snapshot saved: {}", path.display()),
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                    }
+                    // キーリピートはOSに頼らず、押されているかどうかだけを見る
+                    let keyboard_state = event_pump.keyboard_state();
+                    let is_pressed = |code: Keycode| {
+                        keys_down.contains(&code)
+                            || Scancode::from_keycode(code).is_some_and(|scancode| {
+                                keyboard_state.is_scancode_pressed(scancode)
+                            })
                     };
+                    let is_button_pressed = |button: Button| {
+                        buttons_down.contains(&button)
+                            || controllers
+                                .iter()
+                                .any(|controller| controller.button(button))
+                    };
+                    let mut frame_input = read_input(
+                        &input_map,
+                        &mut input,
+                        (is_pressed, is_button_pressed),
+                        (
+                            |code| keys_down.contains(&code),
+                            |button| buttons_down.contains(&button),
+                        ),
+                    );
+                    // フォーカスを失ったら自動で一時停止する
+                    if focus_lost && !game.paused && !frame_input.contains(Command::Pause) {
+                        frame_input.insert(Command::Pause);
+                    }
+                    game.update(frame_input);
+                    if game.is_over && game.puzzle.is_some() {
+                        watching = None;
+                        save_replay(&game, &options);
+                        let mut result = Summary::from_game(&game, settings.fps, &Table::default());
+                        // クリアタイムはフレーム数で比べる
+                        result.high_score = result.cleared == Some(true)
+                            && !game.replay_loaded
+                            && record_clear(&store, &mut records, &game, result.frames);
+                        summary = Some(result);
+                        scene = Scene::GameOver;
+                    } else if game.is_over {
+                        watching = None;
+                        save_replay(&game, &options);
+                        highscore_key_of_game = highscore_key(game.mode, rules_name(&game.rules));
+                        let table = load_game_table(&store, &game);
+                        summary = Some(Summary::from_game(&game, settings.fps, &table));
+                        // リプレイの再生ではハイスコアに登録しない
+                        scene = if store.is_some()
+                            && !game.replay_loaded
+                            && table.rank(&Entry::from_game(&game)).is_some()
+                        {
+                            name_entry = NameEntry::new(&player_name());
+                            Scene::NameEntry
+                        } else {
+                            Scene::GameOver
+                        };
+                    }
+                }
+            }
+            Scene::Stages => {
+                for &key in &menu_keys {
+                    stage_menu.move_cursor(key);
+                    match key {
+                        MenuKey::Select => {
+                            options.stage = Some(stages[stage_menu.cursor].clone());
+                            options.mode = Mode::Puzzle;
+                            options.state = None;
+                            game = start_game(&mut canvas, &options, &settings)?;
+                            scene = Scene::Playing;
+                        }
+                        MenuKey::Back => scene = Scene::Title,
+                        _ => {}
+                    }
+                }
+            }
+            Scene::Versus => {
+                let Some(versus) = &mut versus else {
+                    scene = Scene::Title;
+                    continue;
+                };
+                if keys_down.contains(&Keycode::Escape) {
+                    scene = Scene::Title;
+                } else if versus.outcome.is_some() {
+                    for &key in &menu_keys {
+                        match key {
+                            MenuKey::Select => {
+                                *versus = Versus::new(time_seed(), settings.rules.clone());
+                                println!("random seed = {}", versus.seed);
+                                if versus_ai.is_some() {
+                                    versus_ai = Some(new_versus_ai());
+                                }
+                            }
+                            MenuKey::Back => scene = Scene::Title,
+                            _ => {}
+                        }
+                    }
+                } else {
+                    // 1人目はキーボードの [keys] と1台目、2人目は [keys2] と2台目
+                    let keyboard_state = event_pump.keyboard_state();
+                    let is_pressed = |code: Keycode| {
+                        keys_down.contains(&code)
+                            || Scancode::from_keycode(code).is_some_and(|scancode| {
+                                keyboard_state.is_scancode_pressed(scancode)
+                            })
+                    };
+                    let mut inputs = [Input::NONE; PLAYERS];
+                    let players = [(&input_map, &mut input), (&input_map2, &mut input2)];
+                    for (i, (map, state)) in players.into_iter().enumerate() {
+                        let controller = controllers.get(i);
+                        let is_down = |button: Button| {
+                            controller_buttons_down.iter().any(|&(which, b)| {
+                                b == button && controller.is_some_and(|c| c.instance_id() == which)
+                            })
+                        };
+                        let is_button_pressed = |button: Button| {
+                            is_down(button) || controller.is_some_and(|c| c.button(button))
+                        };
+                        inputs[i] = read_input(
+                            map,
+                            state,
+                            (is_pressed, is_button_pressed),
+                            (|code| keys_down.contains(&code), is_down),
+                        );
+                    }
+                    if let Some(bot) = &mut versus_ai {
+                        inputs[1] = bot.input(&versus.players[1]);
+                    }
+                    if focus_lost
+                        && !versus.players[0].paused
+                        && !inputs.iter().any(|input| input.contains(Command::Pause))
+                    {
+                        inputs[0].insert(Command::Pause);
+                    }
+                    versus.update(inputs);
+                }
+            }
+            Scene::Demo => {
+                // 何か押すか、ゲームオーバーになったらタイトルに戻る
+                if !keys_down.is_empty() || !buttons_down.is_empty() || game.is_over {
+                    scene = Scene::Title;
+                } else {
+                    game.update(demo_bot.input(&game));
+                    game.requested_sounds.clear();
+                }
+            }
+            Scene::Replay => {
+                let Some(viewer) = &mut viewer else {
+                    scene = watched_from;
+                    continue;
+                };
+                let pressed_now = input_map.commands(
+                    |code| keys_down.contains(&code),
+                    |button| buttons_down.contains(&button),
+                );
+                if pressed_now.contains(&Command::Pause) {
+                    viewer.toggle_pause();
+                }
+                if pressed_now.contains(&Command::Step) {
+                    viewer.paused = true;
+                    viewer.step();
+                }
+                if keys_down.contains(&Keycode::Comma) {
+                    viewer.paused = true;
+                    viewer.step_back();
+                }
+                if keys_down.contains(&Keycode::PageUp)
+                    || buttons_down.contains(&Button::LeftShoulder)
+                {
+                    viewer.previous_chain();
+                }
+                if keys_down.contains(&Keycode::PageDown)
+                    || buttons_down.contains(&Button::RightShoulder)
+                {
+                    viewer.next_chain();
+                }
+                if keys_down.contains(&Keycode::Home) {
+                    viewer.seek(0);
+                }
+                for &key in &menu_keys {
+                    match key {
+                        MenuKey::Up => viewer.faster(),
+                        MenuKey::Down => viewer.slower(),
+                        MenuKey::Left => viewer.seek_by(-REPLAY_SEEK_SECONDS),
+                        MenuKey::Right => viewer.seek_by(REPLAY_SEEK_SECONDS),
+                        MenuKey::Back => scene = watched_from,
+                        // 終わりまで見たら結果を出す
+                        MenuKey::Select if viewer.is_finished() => {
+                            game = viewer.game.clone();
+                            let table = load_game_table(&store, &game);
+                            summary = Some(Summary::from_game(&game, settings.fps, &table));
+                            scene = Scene::GameOver;
+                        }
+                        _ => {}
+                    }
+                }
+                if scene == Scene::Replay {
+                    viewer.tick();
+                }
+            }
+            Scene::NameEntry => {
+                name_entry.input(&typed);
+                if keys_down.contains(&Keycode::Backspace) {
+                    name_entry.backspace();
+                }
+                if keys_down.contains(&Keycode::Return) || buttons_down.contains(&Button::A) {
+                    if let Some(store) = &store {
+                        let replay = Replay::from_game(&game, &name_entry.name);
+                        if let Err(e) = store.add(&highscore_key_of_game, &replay) {
+                            println!("{}", e);
+                        }
+                    }
+                    scene = Scene::GameOver;
+                } else if keys_down.contains(&Keycode::Escape) {
+                    scene = Scene::GameOver;
+                }
+            }
+            Scene::GameOver => {
+                for &key in &menu_keys {
+                    match (key, &watching) {
+                        (MenuKey::Select, Some(replay)) => {
+                            viewer = Some(open_viewer(replay));
+                            scene = Scene::Replay;
+                        }
+                        (MenuKey::Select, None) => {
+                            // クリアしたら次のステージ、そうでなければやり直し
+                            let cleared = summary.as_ref().and_then(|s| s.cleared) == Some(true);
+                            let played = stages.iter().position(|stage| {
+                                options.stage.as_ref().is_some_and(|s| s.id == stage.id)
+                            });
+                            if let Some(i) = played.filter(|&i| cleared && i + 1 < stages.len()) {
+                                stage_menu.cursor = i + 1;
+                                options.stage = Some(stages[i + 1].clone());
+                            }
+                            game = start_game(&mut canvas, &options, &settings)?;
+                            scene = Scene::Playing;
+                        }
+                        (MenuKey::Back, Some(_)) => scene = watched_from,
+                        (MenuKey::Back, None) if game.puzzle.is_some() && !stages.is_empty() => {
+                            scene = Scene::Stages
+                        }
+                        (MenuKey::Back, None) => scene = Scene::Title,
+                        _ => {}
+                    }
+                }
+            }
+            Scene::HighScores => {
+                for &key in &menu_keys {
+                    viewer_menu.move_cursor(key);
+                    let delta = match key {
+                        MenuKey::Left => -1,
+                        MenuKey::Right => 1,
+                        _ => 0,
+                    };
+                    if delta != 0 {
+                        let len = (HIGHSCORE_MODES.len() * Rules::PRESET_NAMES.len()) as i32;
+                        viewer_table_index =
+                            (viewer_table_index as i32 + delta).rem_euclid(len) as usize;
+                        viewer_table = load_table(&store, viewer_table_index);
+                        viewer_menu = Menu::new(viewer_table.entries.len().max(1));
+                    }
+                    match key {
+                        MenuKey::Back => scene = Scene::Title,
+                        MenuKey::Select => {
+                            let (Some(store), Some(entry)) =
+                                (&store, viewer_table.entries.get(viewer_menu.cursor))
+                            else {
+                                continue;
+                            };
+                            let loaded = Replay::load(&store.replay_path(entry))
+                                .and_then(|replay| replay.validate().map(|_| replay));
+                            match loaded {
+                                Ok(replay) => {
+                                    viewer = Some(open_viewer(&replay));
+                                    fit_window(&mut canvas, &replay.rules, &settings, 1)?;
+                                    watching = Some(replay);
+                                    watched_from = Scene::HighScores;
+                                    scene = Scene::Replay;
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        _ => {}
+                    }
                 }
-                _ => {}
             }
         }
-        if !game.is_debug || is_keydown {
-            game.update(command);
+
+        match scene {
+            Scene::Title => {
+                let items: Vec<String> = title_items(can_continue)
+                    .iter()
+                    .map(|item| item.label().to_string())
+                    .collect();
+                render_menu(
+                    &mut canvas,
+                    &mut resources,
+                    WINDOW_TITLE,
+                    &items,
+                    &title_menu,
+                )?;
+            }
+            Scene::Options => {
+                let items: Vec<String> = OPTION_ITEMS
+                    .iter()
+                    .map(|&item| settings.label(item))
+                    .collect();
+                render_menu(
+                    &mut canvas,
+                    &mut resources,
+                    "OPTIONS",
+                    &items,
+                    &options_menu,
+                )?;
+            }
+            Scene::Keys => {
+                let mut items: Vec<String> = Bindings::ACTIONS
+                    .iter()
+                    .map(|&action| {
+                        let inputs = if rebinding == Some(action) {
+                            "PRESS A KEY".to_string()
+                        } else {
+                            format!(
+                                "{} / {}",
+                                settings.keys.to_value(action),
+                                settings.buttons.to_value(action)
+                            )
+                        };
+                        format!("{:<14} {}", action.to_uppercase(), inputs)
+                    })
+                    .collect();
+                items.push("BACK".to_string());
+                render_menu(&mut canvas, &mut resources, "KEYS", &items, &keys_menu)?;
+            }
+            Scene::Playing => render(&mut canvas, &game, &mut resources)?,
+            Scene::Stages => {
+                let items: Vec<String> = stages
+                    .iter()
+                    .map(|stage| {
+                        let best = match records.best.get(&stage.id) {
+                            Some(&frames) => format_frames(frames, FPS),
+                            None => "-:--.--".to_string(),
+                        };
+                        format!("{:<3} {:<12} {}", stage.id, stage.name, best)
+                    })
+                    .collect();
+                render_menu(&mut canvas, &mut resources, "PUZZLE", &items, &stage_menu)?;
+            }
+            Scene::Versus => {
+                if let Some(versus) = &versus {
+                    let names = if versus_ai.is_some() {
+                        ["1P", "CPU"]
+                    } else {
+                        ["1P", "2P"]
+                    };
+                    render_versus(&mut canvas, &mut resources, versus, names)?;
+                }
+            }
+            Scene::Demo => {
+                render(&mut canvas, &game, &mut resources)?;
+                render_demo(&mut canvas, &mut resources, &game)?;
+            }
+            Scene::Replay => {
+                if let Some(viewer) = &viewer {
+                    render(&mut canvas, &viewer.game, &mut resources)?;
+                    render_replay_status(&mut canvas, &mut resources, viewer)?;
+                }
+            }
+            Scene::NameEntry => {
+                render(&mut canvas, &game, &mut resources)?;
+                render_name_entry(&mut canvas, &mut resources, &name_entry)?;
+            }
+            Scene::GameOver => {
+                render(&mut canvas, &game, &mut resources)?;
+                if let Some(summary) = &summary {
+                    render_summary(&mut canvas, &mut resources, summary)?;
+                }
+            }
+            Scene::HighScores => {
+                let mut items: Vec<String> = viewer_table
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .map(|(i, entry)| match viewer_table.ranking {
+                        Ranking::Score => {
+                            format!("{:2} {:<10} {:7}", i + 1, entry.name, entry.score)
+                        }
+                        Ranking::Time => format!(
+                            "{:2} {:<10} {:>7}",
+                            i + 1,
+                            entry.name,
+                            format_frames(entry.time, FPS)
+                        ),
+                    })
+                    .collect();
+                if items.is_empty() {
+                    items.push("NO RECORDS".to_string());
+                }
+                let (mode, preset) = viewer_table_key(viewer_table_index);
+                let title = format!("< {} {} >", mode, preset);
+                render_menu(&mut canvas, &mut resources, &title, &items, &viewer_menu)?;
+            }
         }
-        render(&mut canvas, &game, &mut resources)?;
+        canvas.present();
 
-        play_sounds(&mut game, &resources);
+        let sound_games: Vec<&mut Game> = match scene {
+            Scene::Replay => viewer.iter_mut().map(|viewer| &mut viewer.game).collect(),
+            Scene::Versus => versus
+                .iter_mut()
+                .flat_map(|versus| versus.players.iter_mut())
+                .collect(),
+            _ => vec![&mut game],
+        };
+        for sound_game in sound_games {
+            if settings.volume == 0 || options.mute {
+                sound_game.requested_sounds.clear();
+            } else {
+                play_sounds(sound_game, &resources);
+            }
+        }
 
         let finished = SystemTime::now();
         let elapsed = finished.duration_since(started).unwrap();
-        let frame_duration = Duration::new(0, 1_000_000_000u32 / model::FPS as u32);
+        let frame_duration = Duration::new(0, 1_000_000_000u32 / settings.fps as u32);
         if elapsed < frame_duration {
             ::std::thread::sleep(frame_duration - elapsed)
         }
     }
 
+    if scene == Scene::Playing && !game.is_over {
+        save_replay(&game, &options);
+    }
+
+    Ok(())
+}
+
+/// 記録と食い違うリプレイは、最初にずれた所を端末に出す
+fn open_viewer(replay: &Replay) -> Viewer {
+    let viewer = Viewer::new(replay);
+    if let Some(desync) = &viewer.desync {
+        print!("{}", desync.report());
+    }
+    viewer
+}
+
+/// ルールに合わせて画面の大きさを変えてから始める
+fn start_game(
+    canvas: &mut Canvas<Window>,
+    options: &cli::Options,
+    settings: &Settings,
+) -> Result<Game, String> {
+    let game = new_game(options)?;
+    fit_window(canvas, &game.rules, settings, 1)?;
+    Ok(game)
+}
+
+/// players 人分のフィールドが横に並ぶ大きさにする
+fn fit_window(
+    canvas: &mut Canvas<Window>,
+    rules: &Rules,
+    settings: &Settings,
+    players: usize,
+) -> Result<(), String> {
+    let width = screen_width(rules) * players as i32;
+    let height = screen_height(rules);
+    let fullscreen = if settings.fullscreen {
+        FullscreenType::Desktop
+    } else {
+        FullscreenType::Off
+    };
+    if canvas.window().fullscreen_state() != fullscreen {
+        canvas.window_mut().set_fullscreen(fullscreen)?;
+    }
+    if !settings.fullscreen {
+        canvas
+            .window_mut()
+            .set_size(
+                (width as f32 * settings.scale) as u32,
+                (height as f32 * settings.scale) as u32,
+            )
+            .map_err(|e| e.to_string())?;
+    }
+    canvas
+        .set_logical_size(width as u32, height as u32)
+        .map_err(|e| e.to_string())
+}
+
+fn new_versus_ai() -> AiBot {
+    AiBot::new(Weights::default()).with_delay(VERSUS_AI_DELAY)
+}
+
+/// ハイスコア画面の表の番号から、モードとプリセットを決める。モードごとにプリセットを一巡する
+fn viewer_table_key(index: usize) -> (Mode, &'static str) {
+    let presets = Rules::PRESET_NAMES.len();
+    (
+        HIGHSCORE_MODES[index / presets],
+        Rules::PRESET_NAMES[index % presets],
+    )
+}
+
+/// パズルはハイスコア表ではなくクリアタイムを残す
+fn load_records(store: &Option<Store>) -> Records {
+    let Some(store) = store else {
+        return Records::default();
+    };
+    Records::load(&store.dir).unwrap_or_else(|e| {
+        println!("{}", e);
+        Records::default()
+    })
+}
+
+/// クリアタイムを更新したら保存して true
+fn record_clear(store: &Option<Store>, records: &mut Records, game: &Game, frames: i32) -> bool {
+    let (Some(store), Some(puzzle)) = (store, &game.puzzle) else {
+        return false;
+    };
+    if !records.record(&puzzle.stage.id, frames) {
+        return false;
+    }
+    if let Err(e) = records.save(&store.dir) {
+        println!("{}", e);
+    }
+    true
+}
+
+/// ハイスコア画面で選んだモードとプリセットの表
+fn load_table(store: &Option<Store>, index: usize) -> Table {
+    let Some(store) = store else {
+        return Table::default();
+    };
+    let (mode, preset) = viewer_table_key(index);
+    let key = highscore_key(mode, preset);
+    store.load(&key, Ranking::of(mode)).unwrap_or_else(|e| {
+        println!("{}", e);
+        Table::new(Ranking::of(mode))
+    })
+}
+
+/// 終わったゲームのモードとルールの表
+fn load_game_table(store: &Option<Store>, game: &Game) -> Table {
+    let ranking = Ranking::of(game.mode);
+    let Some(store) = store else {
+        return Table::new(ranking);
+    };
+    let key = highscore_key(game.mode, rules_name(&game.rules));
+    store.load(&key, ranking).unwrap_or_else(|e| {
+        println!("{}", e);
+        Table::new(ranking)
+    })
+}
+
+fn set_volume(settings: &Settings, options: &cli::Options) {
+    if !options.mute {
+        mixer::Channel::all()
+            .set_volume(settings.volume * mixer::MAX_VOLUME / settings::MAX_VOLUME);
+    }
+}
+
+/// 設定の割り当てを SDL のキーとボタンに解決したもの
+struct InputMap {
+    keys: Vec<(Keycode, Command)>,
+    buttons: Vec<(Button, Command)>,
+}
+
+impl InputMap {
+    fn new(keys: &Bindings, section: &str, buttons: &Bindings) -> Result<InputMap, String> {
+        let mut key_map = Vec::new();
+        for (name, command) in keys.commands() {
+            let code = Keycode::from_name(name)
+                .ok_or(format!("unknown key in [{}]: {}", section, name))?;
+            key_map.push((code, command));
+        }
+        let mut button_map = Vec::new();
+        for (name, command) in buttons.commands() {
+            let button = Button::from_string(name)
+                .ok_or(format!("unknown button in [buttons]: {}", name))?;
+            button_map.push((button, command));
+        }
+        Ok(InputMap {
+            keys: key_map,
+            buttons: button_map,
+        })
+    }
+
+    /// 1人目と、対戦の2人目。ボタンの割り当ては共通
+    fn for_players(settings: &Settings) -> Result<(InputMap, InputMap), String> {
+        Ok((
+            InputMap::new(&settings.keys, "keys", &settings.buttons)?,
+            InputMap::new(&settings.keys2, "keys2", &settings.buttons)?,
+        ))
+    }
+
+    /// 押されているキーとボタンが出すコマンド
+    fn commands(
+        &self,
+        is_key_pressed: impl Fn(Keycode) -> bool,
+        is_button_pressed: impl Fn(Button) -> bool,
+    ) -> Vec<Command> {
+        let keys = self
+            .keys
+            .iter()
+            .filter(|(code, _)| is_key_pressed(*code))
+            .map(|(_, command)| *command);
+        let buttons = self
+            .buttons
+            .iter()
+            .filter(|(button, _)| is_button_pressed(*button))
+            .map(|(_, command)| *command);
+        keys.chain(buttons).collect()
+    }
+}
+
+/// 1人分の1フレームの入力。held は押されているか、down はこのフレームに押されたか
+fn read_input(
+    input_map: &InputMap,
+    input: &mut InputState,
+    held: (impl Fn(Keycode) -> bool, impl Fn(Button) -> bool),
+    down: (impl Fn(Keycode) -> bool, impl Fn(Button) -> bool),
+) -> Input {
+    let mut buttons = Buttons::default();
+    for command in input_map.commands(held.0, held.1) {
+        buttons.press(command);
+    }
+    let mut frame_input = input.update(buttons);
+    // 一時停止とコマ送りは押した瞬間だけ
+    for command in input_map.commands(down.0, down.1) {
+        if command == Command::Pause || command == Command::Step {
+            frame_input.insert(command);
+        }
+    }
+    frame_input
+}
+
+/// メニューはキーの割り当てによらず、矢印キーと十字ボタンで操作する
+fn menu_keys(keys_down: &[Keycode], buttons_down: &[Button]) -> Vec<MenuKey> {
+    let keys = keys_down.iter().filter_map(|code| match *code {
+        Keycode::Up => Some(MenuKey::Up),
+        Keycode::Down => Some(MenuKey::Down),
+        Keycode::Left => Some(MenuKey::Left),
+        Keycode::Right => Some(MenuKey::Right),
+        Keycode::Return | Keycode::Space => Some(MenuKey::Select),
+        Keycode::Escape => Some(MenuKey::Back),
+        _ => None,
+    });
+    let buttons = buttons_down.iter().filter_map(|button| match *button {
+        Button::DPadUp => Some(MenuKey::Up),
+        Button::DPadDown => Some(MenuKey::Down),
+        Button::DPadLeft => Some(MenuKey::Left),
+        Button::DPadRight => Some(MenuKey::Right),
+        Button::A | Button::Start => Some(MenuKey::Select),
+        Button::B => Some(MenuKey::Back),
+        _ => None,
+    });
+    keys.chain(buttons).collect()
+}
+
+/// ウィンドウを開かずに最後まで進めて結果を表示する
+fn run_headless(options: &cli::Options) -> Result<(), String> {
+    let game = new_game(options)?;
+    // リプレイが無いときは無操作で、ゲームオーバーまで進める
+    let game = if game.replay_loaded {
+        let commands = game.commands.len() as i32;
+        sim::run_bot(game, &mut bot::IdleBot, commands)
+    } else {
+        sim::run_bot(game, &mut bot::IdleBot, i32::MAX)
+    };
+    save_replay(&game, options);
+    println!("{}", sim::Stats::CSV_HEADER);
+    println!("{}", sim::Stats::from_game(&game).to_csv());
+    if let Some(path) = &options.replay {
+        let replay = Replay::load(path)?;
+        if let Some(desync) = desync::Desync::find(&replay.checksums, &game.checksums) {
+            eprint!("{}", desync.report());
+            return Err(format!("replay desynced at frame {}", desync.frame()));
+        }
+    }
     Ok(())
 }
 
+fn new_game(options: &cli::Options) -> Result<Game, String> {
+    if let Some(path) = &options.state {
+        let game = Game::load_snapshot(path)?;
+        println!(
+            "snapshot loaded: seed = {}, frame = {}",
+            game.seed, game.frame
+        );
+        return Ok(game);
+    }
+    if let Some(path) = &options.replay {
+        let replay = Replay::load(path)?;
+        replay.validate()?;
+        println!("replay loaded: seed = {}", replay.seed);
+        return Ok(Game::from_replay(&replay));
+    }
+    if let Some(stage) = &options.stage {
+        println!("stage {}: {}", stage.id, stage.name);
+        return Ok(Game::from_stage(stage));
+    }
+    let game = match options.seed {
+        Some(seed) => Game::with_seed(seed, options.rules.clone()),
+        None => Game::new(options.rules.clone()),
+    };
+    let game = game.with_mode(options.mode);
+    println!("random seed = {}", game.seed);
+    Ok(game)
+}
+
+fn save_replay(game: &Game, options: &cli::Options) {
+    let Some(path) = &options.record else {
+        return;
+    };
+    if game.replay_loaded {
+        return;
+    }
+    if let Err(e) = Replay::from_game(game, &player_name()).save(path) {
+        println!("{}", e);
+    }
+}
+
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn player_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
 fn init_mixer() {
     let chunk_size = 1_024;
     mixer::open_audio(
@@ -212,37 +1259,52 @@ fn render(
 ) -> Result<(), String> {
     canvas.set_draw_color(Color::RGB(32, 32, 32));
     canvas.clear();
+    render_game(canvas, game, resources)
+}
 
+/// 画面を消さずにフィールドと情報欄を描く。対戦ではビューポートをずらして2回呼ぶ
+fn render_game(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    resources: &mut Resources,
+) -> Result<(), String> {
     let font = resources.fonts.get_mut("boxfont").unwrap();
 
     canvas.set_draw_color(Color::RGB(255, 128, 128));
 
     // render field
-    for y in INVISIBLE_ROW_COUNT..FIELD_H {
-        for x in 0..FIELD_W {
+    let screen_width = screen_width(&game.rules);
+    let screen_height = screen_height(&game.rules);
+
+    for y in INVISIBLE_ROW_COUNT..game.rules.field_h {
+        for x in 0..game.rules.field_w {
             if game.field[y][x] != EMPTY {
                 let color;
                 if game.state == State::Flashing && game.check_erase_result[y][x] {
                     if game.flashing_wait % 2 == 0 {
                         color = Color::RGB(255, 255, 255);
                     } else {
-                        color = get_block_color(game.field[y][x]);
+                        color = get_jewel_color(game, game.field[y][x]);
                     }
                 } else {
-                    color = get_block_color(game.field[y][x]);
+                    color = get_jewel_color(game, game.field[y][x]);
                 }
                 let offset_y = if game.state == State::PieceFalling && game.piece_falling[y][x] {
                     game.piece_fall_offset
                 } else {
                     0
                 };
-                canvas.set_draw_color(color);
-                canvas.fill_rect(Rect::new(
-                    (x as i32) * (CELL_SIZE as i32),
-                    (y as i32 - INVISIBLE_ROW_COUNT as i32) * (CELL_SIZE as i32) + offset_y,
-                    CELL_SIZE as u32,
-                    CELL_SIZE as u32,
-                ))?;
+                render_jewel(
+                    canvas,
+                    game.field[y][x],
+                    color,
+                    Rect::new(
+                        (x as i32) * (CELL_SIZE as i32),
+                        (y as i32 - INVISIBLE_ROW_COUNT as i32) * (CELL_SIZE as i32) + offset_y,
+                        CELL_SIZE as u32,
+                        CELL_SIZE as u32,
+                    ),
+                )?;
             }
         }
     }
@@ -250,36 +1312,49 @@ fn render(
     // render info
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.fill_rect(Rect::new(
-        SCREEN_WIDTH - INFO_WIDTH,
+        screen_width - INFO_WIDTH,
         0,
         INFO_WIDTH as u32,
-        SCREEN_HEIGHT as u32,
+        screen_height as u32,
     ))?;
 
     // render current block
     if game.state == State::Controllable {
-        for i in 0..BLOCK_LEN {
-            let color = get_block_color(game.current[i]);
-            canvas.set_draw_color(color);
-            canvas.fill_rect(Rect::new(
-                (game.current_x as i32) * (CELL_SIZE as i32),
-                ((game.current_y + i) as i32 - INVISIBLE_ROW_COUNT as i32) * (CELL_SIZE as i32),
-                CELL_SIZE as u32,
-                CELL_SIZE as u32,
-            ))?;
+        for i in 0..game.rules.block_len {
+            // パズルでピースを使い切ると空になる
+            if game.current[i] == EMPTY {
+                continue;
+            }
+            render_jewel(
+                canvas,
+                game.current[i],
+                get_jewel_color(game, game.current[i]),
+                Rect::new(
+                    (game.current_x as i32) * (CELL_SIZE as i32),
+                    ((game.current_y + i) as i32 - INVISIBLE_ROW_COUNT as i32) * (CELL_SIZE as i32),
+                    CELL_SIZE as u32,
+                    CELL_SIZE as u32,
+                ),
+            )?;
         }
     }
 
     // render next block
-    for i in 0..BLOCK_LEN {
-        let color = get_block_color(game.next_to_display[i]);
-        canvas.set_draw_color(color);
-        canvas.fill_rect(Rect::new(
-            (FIELD_W as i32 + 1) * (CELL_SIZE as i32),
-            (i as i32) * (CELL_SIZE as i32),
-            CELL_SIZE as u32,
-            CELL_SIZE as u32,
-        ))?;
+    for i in 0..game.rules.block_len {
+        if game.next_to_display[i] == EMPTY {
+            continue;
+        }
+        render_jewel(
+            canvas,
+            game.next_to_display[i],
+            get_jewel_color(game, game.next_to_display[i]),
+            Rect::new(
+                (game.rules.field_w as i32 + 1) * (CELL_SIZE as i32),
+                (i as i32) * (CELL_SIZE as i32),
+                CELL_SIZE as u32,
+                CELL_SIZE as u32,
+            ),
+        )?;
     }
 
     let font_color = Color::RGB(224, 224, 224);
@@ -287,7 +1362,7 @@ fn render(
         canvas,
         font,
         format!("JEWELS {:6}", game.total_erased).to_string(),
-        SCREEN_WIDTH - INFO_WIDTH + 20,
+        screen_width - INFO_WIDTH + 20,
         230,
         font_color,
         false,
@@ -296,11 +1371,95 @@ fn render(
         canvas,
         font,
         format!("MAX ERASE {:3}", game.max_erased_at_one_time).to_string(),
-        SCREEN_WIDTH - INFO_WIDTH + 20,
+        screen_width - INFO_WIDTH + 20,
         270,
         font_color,
         false,
     );
+    render_font(
+        canvas,
+        font,
+        format!("SCORE {:7}", game.score).to_string(),
+        screen_width - INFO_WIDTH + 20,
+        310,
+        font_color,
+        false,
+    );
+    render_font(
+        canvas,
+        font,
+        format!("LEVEL {:7}", game.level).to_string(),
+        screen_width - INFO_WIDTH + 20,
+        350,
+        font_color,
+        false,
+    );
+
+    if let Some(puzzle) = &game.puzzle {
+        let goal = match puzzle.stage.goal {
+            Goal::Target(..) => "GOAL   TARGET".to_string(),
+            Goal::Jewels(count) => format!("GOAL {:4}/{:3}", game.total_erased.min(count), count),
+            Goal::Chain(count) => format!("GOAL  CHAIN {}", count),
+        };
+        let lines = [goal, format!("PIECES {:6}", game.pieces_left())];
+        for (i, line) in lines.into_iter().enumerate() {
+            render_font(
+                canvas,
+                font,
+                line,
+                screen_width - INFO_WIDTH + 20,
+                390 + i as i32 * 40,
+                font_color,
+                false,
+            );
+        }
+        // 目標の宝石を点滅する枠で示す
+        if let Some((x, y)) = puzzle.target.filter(|_| game.frame % 10 < 5) {
+            canvas.set_draw_color(Color::RGB(255, 255, 255));
+            for inset in 0..3 {
+                canvas.draw_rect(Rect::new(
+                    x as i32 * CELL_SIZE + inset,
+                    (y as i32 - INVISIBLE_ROW_COUNT as i32) * CELL_SIZE + inset,
+                    (CELL_SIZE - inset * 2) as u32,
+                    (CELL_SIZE - inset * 2) as u32,
+                ))?;
+            }
+        }
+    }
+
+    if game.mode == Mode::Pressure {
+        // 次にせり上がるまでの秒数
+        render_font(
+            canvas,
+            font,
+            format!("RISE {:8}", (game.pressure_wait + FPS - 1) / FPS).to_string(),
+            screen_width - INFO_WIDTH + 20,
+            390,
+            font_color,
+            false,
+        );
+    }
+
+    if let Some(frames) = game.timer_frames() {
+        let mut lines = vec![format!("TIME {:>9}", format_frames(frames, FPS))];
+        if game.mode == Mode::Sprint {
+            lines.push(format!(
+                "LEFT {:9}",
+                (SPRINT_JEWELS - game.total_erased).max(0)
+            ));
+        }
+        for (i, line) in lines.into_iter().enumerate() {
+            render_font(
+                canvas,
+                font,
+                line,
+                screen_width - INFO_WIDTH + 20,
+                390 + i as i32 * 40,
+                font_color,
+                false,
+            );
+        }
+    }
 
     for i in 0..game.combo {
         render_font(
@@ -309,18 +1468,372 @@ fn render(
             format!("COMBO!").to_string(),
             (game.current_x + 1) as i32 * CELL_SIZE,
             (game.current_y as i32 - INVISIBLE_ROW_COUNT as i32 + 1 + i) as i32 * CELL_SIZE,
-            get_block_color(1 + (game.frame + i) % COLOR_COUNT),
+            get_block_color(1 + (game.frame + i) % game.rules.color_count),
             false,
         );
     }
 
-    if game.is_over {
+    let cleared = game.puzzle.as_ref().is_some_and(|puzzle| puzzle.cleared)
+        || game.timer.as_ref().is_some_and(|timer| timer.finished);
+    if game.is_over && !cleared {
         canvas.set_draw_color(Color::RGBA(255, 0, 0, 128));
-        canvas.fill_rect(Rect::new(0, 0, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32))?;
+        canvas.fill_rect(Rect::new(0, 0, screen_width as u32, screen_height as u32))?;
+    } else if game.paused {
+        let field_width = screen_width - INFO_WIDTH;
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+        canvas.fill_rect(Rect::new(0, 0, field_width as u32, screen_height as u32))?;
+        render_font(
+            canvas,
+            font,
+            "PAUSE".to_string(),
+            field_width / 2,
+            screen_height / 2 - 40,
+            font_color,
+            true,
+        );
+        render_font(
+            canvas,
+            font,
+            "P: RESUME  .: STEP".to_string(),
+            field_width / 2,
+            screen_height / 2,
+            font_color,
+            true,
+        );
+    } else if let Some(timer) = &game.timer {
+        // スタートの合図。GO! はスタートしてから少しだけ出す
+        let text = if timer.countdown > 0 {
+            Some(((timer.countdown + FPS - 1) / FPS).to_string())
+        } else if timer.frames < FPS / 2 {
+            Some("GO!".to_string())
+        } else {
+            None
+        };
+        if let Some(text) = text {
+            render_font(
+                canvas,
+                font,
+                text,
+                (screen_width - INFO_WIDTH) / 2,
+                screen_height / 2 - 20,
+                Color::RGB(255, 255, 128),
+                true,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn render_menu(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    title: &str,
+    items: &[String],
+    menu: &Menu,
+) -> Result<(), String> {
+    canvas.set_draw_color(Color::RGB(32, 32, 32));
+    canvas.clear();
+
+    let font = resources.fonts.get_mut("boxfont").unwrap();
+    let center = canvas.logical_size().0 as i32 / 2;
+    render_font(
+        canvas,
+        font,
+        title.to_uppercase(),
+        center,
+        40,
+        get_block_color(1),
+        true,
+    );
+    // 項目が多いときは画面に収まるよう間を詰める
+    let height = canvas.logical_size().1 as i32;
+    let step = ((height - 160) / items.len().saturating_sub(1).max(1) as i32).clamp(24, 40);
+    for (i, item) in items.iter().enumerate() {
+        // 選択中の項目は色を変える
+        let color = if i == menu.cursor {
+            Color::RGB(255, 255, 128)
+        } else {
+            Color::RGB(160, 160, 160)
+        };
+        render_font(
+            canvas,
+            font,
+            item.clone(),
+            center,
+            120 + i as i32 * step,
+            color,
+            true,
+        );
+    }
+    Ok(())
+}
+
+fn render_summary(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    summary: &Summary,
+) -> Result<(), String> {
+    let (width, height) = canvas.logical_size();
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(0, 0, width, height))?;
+
+    let font = resources.fonts.get_mut("boxfont").unwrap();
+    let center = width as i32 / 2;
+    let font_color = Color::RGB(224, 224, 224);
+    let title = match (summary.mode, summary.cleared) {
+        (Mode::Puzzle, Some(true)) => "STAGE CLEAR",
+        (Mode::Puzzle, _) => "FAILED",
+        (Mode::Sprint, Some(true)) => "FINISH!",
+        (Mode::Ultra, Some(true)) => "TIME UP",
+        _ => "GAME OVER",
+    };
+    let time = match summary.cleared {
+        Some(_) => format_frames(summary.frames, FPS),
+        None => summary.time_played(),
+    };
+    let mut lines = vec![
+        title.to_string(),
+        String::new(),
+        format!("SCORE {:9}", summary.score),
+        format!("JEWELS {:8}", summary.jewels),
+        format!("MAX COMBO {:5}", summary.max_combo),
+        format!("MAX ERASE {:5}", summary.max_erase),
+        format!("TIME {:>10}", time),
+        String::new(),
+    ];
+    if summary.high_score && matches!(summary.mode, Mode::Puzzle | Mode::Sprint) {
+        lines.push("NEW BEST TIME!".to_string());
+    } else if summary.high_score {
+        lines.push("NEW HIGH SCORE!".to_string());
     }
+    for (i, line) in lines.iter().enumerate() {
+        if !line.is_empty() {
+            render_font(
+                canvas,
+                font,
+                line.clone(),
+                center,
+                40 + i as i32 * 40,
+                font_color,
+                true,
+            );
+        }
+    }
+    render_font(
+        canvas,
+        font,
+        match (summary.mode, summary.cleared) {
+            (Mode::Puzzle, Some(true)) => "ENTER: NEXT  ESC: STAGES",
+            (Mode::Puzzle, _) => "ENTER: RETRY  ESC: STAGES",
+            _ => "ENTER: RETRY  ESC: TITLE",
+        }
+        .to_string(),
+        center,
+        height as i32 - 60,
+        Color::RGB(160, 160, 160),
+        true,
+    );
+    Ok(())
+}
 
-    canvas.present();
+fn render_versus(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    versus: &Versus,
+    names: [&str; PLAYERS],
+) -> Result<(), String> {
+    canvas.set_draw_color(Color::RGB(32, 32, 32));
+    canvas.clear();
+    let (width, height) = canvas.logical_size();
+    let player_width = width / PLAYERS as u32;
+    for (i, game) in versus.players.iter().enumerate() {
+        canvas.set_viewport(Rect::new(
+            i as i32 * player_width as i32,
+            0,
+            player_width,
+            height,
+        ));
+        render_game(canvas, game, resources)?;
+        let font = resources.fonts.get_mut("boxfont").unwrap();
+        let x = player_width as i32 - INFO_WIDTH + 20;
+        render_font(
+            canvas,
+            font,
+            names[i].to_string(),
+            x,
+            390,
+            get_block_color(1 + i as i32),
+            false,
+        );
+        if versus.garbage[i] > 0 {
+            render_font(
+                canvas,
+                font,
+                format!("GARBAGE {:5}", versus.garbage[i]),
+                x,
+                430,
+                Color::RGB(255, 128, 128),
+                false,
+            );
+        }
+    }
+    canvas.set_viewport(None);
+
+    let Some(outcome) = versus.outcome else {
+        return Ok(());
+    };
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(0, 0, width, height))?;
+    let font = resources.fonts.get_mut("boxfont").unwrap();
+    let center = width as i32 / 2;
+    let text = match outcome {
+        Outcome::Winner(i) => format!("{} WINS", names[i]),
+        Outcome::Draw => "DRAW".to_string(),
+    };
+    render_font(
+        canvas,
+        font,
+        text,
+        center,
+        height as i32 / 2 - 40,
+        Color::RGB(224, 224, 224),
+        true,
+    );
+    render_font(
+        canvas,
+        font,
+        "ENTER: REMATCH  ESC: TITLE".to_string(),
+        center,
+        height as i32 - 60,
+        Color::RGB(160, 160, 160),
+        true,
+    );
+    Ok(())
+}
 
+/// デモ中の表示。フィールドの上に点滅させる
+fn render_demo(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    game: &Game,
+) -> Result<(), String> {
+    if game.frame % FPS >= FPS / 2 {
+        return Ok(());
+    }
+    let font = resources.fonts.get_mut("boxfont").unwrap();
+    let field_width = screen_width(&game.rules) - INFO_WIDTH;
+    render_font(
+        canvas,
+        font,
+        "DEMO".to_string(),
+        field_width / 2,
+        screen_height(&game.rules) / 2 - 40,
+        Color::RGB(255, 255, 128),
+        true,
+    );
+    render_font(
+        canvas,
+        font,
+        "PRESS ANY KEY".to_string(),
+        field_width / 2,
+        screen_height(&game.rules) / 2,
+        Color::RGB(255, 255, 128),
+        true,
+    );
+    Ok(())
+}
+
+/// リプレイ再生中の表示。情報欄の下に速度と再生位置を出す
+fn render_replay_status(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    viewer: &Viewer,
+) -> Result<(), String> {
+    let (width, height) = canvas.logical_size();
+    let font = resources.fonts.get_mut("boxfont").unwrap();
+    let x = width as i32 - INFO_WIDTH + 20;
+    let time = |frames: i32| format!("{}:{:02}", frames / FPS / 60, frames / FPS % 60);
+    let state = if viewer.is_desynced() {
+        "DESYNC".to_string()
+    } else if viewer.is_finished() {
+        "END".to_string()
+    } else if viewer.paused {
+        "PAUSED".to_string()
+    } else {
+        format!("{}x", viewer.speed())
+    };
+    let lines = [
+        format!("REPLAY {:>7}", state),
+        format!(
+            "{:>14}",
+            format!("{}/{}", time(viewer.position()), time(viewer.length()))
+        ),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        render_font(
+            canvas,
+            font,
+            line.clone(),
+            x,
+            height as i32 - 120 + i as i32 * 40,
+            Color::RGB(255, 128, 128),
+            false,
+        );
+    }
+    if viewer.is_finished() {
+        render_font(
+            canvas,
+            font,
+            "ENTER: RESULT".to_string(),
+            (width as i32 - INFO_WIDTH) / 2,
+            height as i32 - 40,
+            Color::RGB(160, 160, 160),
+            true,
+        );
+    }
+    Ok(())
+}
+
+fn render_name_entry(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    name_entry: &NameEntry,
+) -> Result<(), String> {
+    let (width, height) = canvas.logical_size();
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(0, 0, width, height))?;
+
+    let font = resources.fonts.get_mut("boxfont").unwrap();
+    let center = width as i32 / 2;
+    let font_color = Color::RGB(224, 224, 224);
+    render_font(
+        canvas,
+        font,
+        "HIGH SCORE!".to_string(),
+        center,
+        120,
+        get_block_color(1),
+        true,
+    );
+    render_font(
+        canvas,
+        font,
+        "ENTER YOUR NAME".to_string(),
+        center,
+        200,
+        font_color,
+        true,
+    );
+    render_font(
+        canvas,
+        font,
+        format!("{}_", name_entry.name),
+        center,
+        260,
+        Color::RGB(255, 255, 128),
+        true,
+    );
     Ok(())
 }
 
@@ -366,6 +1879,36 @@ fn play_sounds(game: &mut Game, resources: &Resources) {
     game.requested_sounds = Vec::new();
 }
 
+fn render_jewel(
+    canvas: &mut Canvas<Window>,
+    color_num: i32,
+    color: Color,
+    rect: Rect,
+) -> Result<(), String> {
+    canvas.set_draw_color(color);
+    canvas.fill_rect(rect)?;
+    // マジックジュエルは白枠で区別する
+    if color_num == MAGIC {
+        canvas.set_draw_color(Color::RGB(255, 255, 255));
+        canvas.draw_rect(Rect::new(
+            rect.x() + 4,
+            rect.y() + 4,
+            rect.width() - 8,
+            rect.height() - 8,
+        ))?;
+    }
+    Ok(())
+}
+
+// マジックジュエルは色が順に変わる
+fn get_jewel_color(game: &Game, color_num: i32) -> Color {
+    if color_num == MAGIC {
+        get_block_color(1 + (game.frame / 3).rem_euclid(game.rules.color_count))
+    } else {
+        get_block_color(color_num)
+    }
+}
+
 fn get_block_color(color_num: i32) -> Color {
     match color_num {
         1 => Color::RGB(255, 128, 128),
@@ -374,6 +1917,8 @@ fn get_block_color(color_num: i32) -> Color {
         4 => Color::RGB(255, 255, 128),
         5 => Color::RGB(128, 255, 255),
         6 => Color::RGB(255, 128, 255),
+        7 => Color::RGB(255, 192, 64),
+        8 => Color::RGB(192, 192, 192),
         _ => panic!(),
     }
 }
@@ -0,0 +1,204 @@
+//! Single-player game modes.
+//!
+//! Endless is the original game: play until the field overflows. In pressure
+//! mode a row of jewels rises from the bottom every few seconds (see
+//! [`Game::push_rows`](crate::model::Game::push_rows)), sooner as the level
+//! goes up, so the stack has to be kept down by erasing. Puzzle mode plays a
+//! stage with a fixed start and goal (see [`crate::puzzle`]).
+//!
+//! Sprint and ultra are timed: sprint ends when [`SPRINT_JEWELS`] jewels have
+//! been erased and is ranked by the time it took, ultra ends after
+//! [`ULTRA_FRAMES`] and is ranked by score. Both start with a countdown and
+//! keep their clock in a [`Timer`].
+
+use crate::model::{Game, FPS};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Endless,
+    Pressure, // 一定時間ごとに下から1段せり上がる
+    Puzzle,   // ステージの目標を達成したらクリア
+    Sprint,   // 決まった個数を消すまでのタイムを競う
+    Ultra,    // 制限時間内の得点を競う
+}
+
+impl Mode {
+    pub const ALL: [Mode; 5] = [
+        Mode::Endless,
+        Mode::Pressure,
+        Mode::Puzzle,
+        Mode::Sprint,
+        Mode::Ultra,
+    ];
+
+    /// 設定ファイルやハイスコア表のキーに使う名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Endless => "endless",
+            Mode::Pressure => "pressure",
+            Mode::Puzzle => "puzzle",
+            Mode::Sprint => "sprint",
+            Mode::Ultra => "ultra",
+        }
+    }
+
+    /// 時間を計るモード
+    pub fn is_timed(self) -> bool {
+        matches!(self, Mode::Sprint | Mode::Ultra)
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Mode, String> {
+        Mode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == str)
+            .ok_or(format!("unknown mode: {}", str))
+    }
+}
+
+pub const PRESSURE_WAIT: i32 = FPS * 8;
+pub const PRESSURE_WAIT_STEP: i32 = FPS / 2; // 1レベルごとに縮める
+pub const MIN_PRESSURE_WAIT: i32 = FPS * 2;
+
+/// レベルに応じたせり上がりの間隔
+pub fn pressure_wait(level: i32) -> i32 {
+    (PRESSURE_WAIT - level * PRESSURE_WAIT_STEP).max(MIN_PRESSURE_WAIT)
+}
+
+pub const SPRINT_JEWELS: i32 = 100;
+pub const ULTRA_FRAMES: i32 = FPS * 120;
+pub const COUNTDOWN_FRAMES: i32 = FPS * 3;
+
+/// スプリントとウルトラの時計
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timer {
+    pub countdown: i32, // スタートまでの残り。この間は操作もウェイトも止まる
+    pub frames: i32,    // スタートしてからのフレーム数。一時停止中は進まない
+    pub finished: bool, // 目標の個数を消したか、時間切れまで生き残った
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            countdown: COUNTDOWN_FRAMES,
+            ..Default::default()
+        }
+    }
+}
+
+impl Game {
+    /// カウントダウン中なら false を返して、このフレームは何もしない
+    pub(crate) fn tick_timer(&mut self) -> bool {
+        let Some(timer) = &mut self.timer else {
+            return true;
+        };
+        if timer.countdown > 0 {
+            timer.countdown -= 1;
+            return false;
+        }
+        timer.frames += 1;
+        true
+    }
+
+    /// フレームの最後に呼ぶ。連鎖の途中でも、達成したフレームで終わる
+    pub(crate) fn check_timer(&mut self) {
+        let Some(timer) = &mut self.timer else {
+            return;
+        };
+        if self.is_over {
+            return;
+        }
+        timer.finished = match self.mode {
+            Mode::Sprint => self.total_erased >= SPRINT_JEWELS,
+            _ => timer.frames >= ULTRA_FRAMES,
+        };
+        if timer.finished {
+            self.is_over = true;
+        }
+    }
+
+    /// 完走したときのタイム。それ以外は 0
+    pub fn clear_time(&self) -> i32 {
+        match &self.timer {
+            Some(timer) if timer.finished => timer.frames,
+            _ => 0,
+        }
+    }
+
+    /// 情報欄に出す時間。スプリントは経過時間、ウルトラは残り時間
+    pub fn timer_frames(&self) -> Option<i32> {
+        let timer = self.timer.as_ref()?;
+        Some(match self.mode {
+            Mode::Ultra => ULTRA_FRAMES - timer.frames,
+            _ => timer.frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Command, State};
+    use crate::rules::Rules;
+
+    #[test]
+    fn test_mode() {
+        for mode in Mode::ALL {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        assert!("marathon".parse::<Mode>().is_err());
+        assert_eq!(pressure_wait(0), PRESSURE_WAIT);
+        assert!(pressure_wait(1) < PRESSURE_WAIT);
+        assert_eq!(pressure_wait(1000), MIN_PRESSURE_WAIT);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut game = Game::with_seed(3, Rules::default()).with_mode(Mode::Sprint);
+        // カウントダウン中は動かせない
+        let x = game.current_x;
+        for _ in 0..COUNTDOWN_FRAMES {
+            game.update(Command::Left);
+        }
+        assert_eq!(game.current_x, x);
+        assert_eq!(game.timer_frames(), Some(0));
+        game.update(Command::Left);
+        assert_eq!(game.current_x, x - 1);
+
+        // 一時停止中は時計も止まる
+        game.update(Command::Pause);
+        game.update(Command::None);
+        game.update(Command::Pause);
+        assert_eq!(game.timer_frames(), Some(2));
+
+        game.total_erased = SPRINT_JEWELS;
+        game.update(Command::None);
+        assert!(game.is_over);
+        assert_eq!(game.clear_time(), 3);
+
+        let mut game = Game::with_seed(3, Rules::default()).with_mode(Mode::Ultra);
+        for _ in 0..COUNTDOWN_FRAMES + ULTRA_FRAMES - 1 {
+            game.update(Command::None);
+            if game.state == State::Controllable {
+                game.current_y = 0; // 積み上がらないように浮かせておく
+            }
+        }
+        assert_eq!(game.timer_frames(), Some(1));
+        assert!(!game.is_over);
+        game.update(Command::None);
+        assert!(game.is_over);
+        assert_eq!(game.clear_time(), ULTRA_FRAMES);
+        assert_eq!(Game::with_seed(3, Rules::default()).clear_time(), 0);
+    }
+}
@@ -1,18 +1,17 @@
+use crate::desync::Checksum;
+use crate::mode::{self, Mode, Timer};
+use crate::puzzle::Puzzle;
+use crate::replay::Replay;
+use crate::rules::Rules;
+use crate::score;
 use rand::prelude::*;
-use std::{fs::File, io::Write, time};
+use std::{fmt, str::FromStr, time};
 
 pub const FPS: i32 = 30;
-pub const FIELD_W: usize = 6;
-pub const FIELD_H: usize = 16;
 pub const INVISIBLE_ROW_COUNT: usize = 3;
 pub const CELL_SIZE: i32 = 40;
-pub const COLOR_COUNT: i32 = 6;
-pub const BLOCK_LEN: usize = 3; // 1ブロックのピース数
-pub const ERASE_LEN: usize = 3; // この個数つながったら消す
-pub const FALL_WAIT: i32 = 30;
-pub const FLASHING_WAIT: i32 = 15;
-pub const PIECE_FALL_SPEED: i32 = 15;
 pub const EMPTY: i32 = 0;
+pub const MAGIC: i32 = -1; // 着地した下の宝石と同じ色をすべて消す
 
 // $varの値が
 //   > 0 : ウェイト中
@@ -34,20 +33,119 @@ pub enum Command {
     None,
     Left,
     Right,
-    Rotate,
-    Down,
+    Rotate,        // 一番下の宝石を一番上へ
+    RotateReverse, // 一番上の宝石を一番下へ
+    SoftDrop,      // 押している間、速く落とす
+    HardDrop,      // 着地するまで一気に落とす
+    Pause,         // 一時停止と再開を切り替える
+    Step,          // 一時停止中に1フレームだけ進める
 }
 
-impl Command {
-    pub fn from_str(str: &str) -> Command {
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Command, String> {
         match str {
-            "None" => Command::None,
-            "Left" => Command::Left,
-            "Right" => Command::Right,
-            "Rotate" => Command::Rotate,
-            "Down" => Command::Down,
-            _ => Command::None,
+            "None" => Ok(Command::None),
+            "Left" => Ok(Command::Left),
+            "Right" => Ok(Command::Right),
+            "Rotate" => Ok(Command::Rotate),
+            "RotateReverse" => Ok(Command::RotateReverse),
+            "SoftDrop" => Ok(Command::SoftDrop),
+            "HardDrop" => Ok(Command::HardDrop),
+            "Pause" => Ok(Command::Pause),
+            "Step" => Ok(Command::Step),
+            _ => Err(format!("unknown command: {}", str)),
+        }
+    }
+}
+
+impl Command {
+    /// 同じフレームに複数あるときに処理する順。Pause と Step は update で先に見る
+    pub const ORDER: [Command; 8] = [
+        Command::Rotate,
+        Command::RotateReverse,
+        Command::Left,
+        Command::Right,
+        Command::SoftDrop,
+        Command::HardDrop,
+        Command::Pause,
+        Command::Step,
+    ];
+
+    fn bit(self) -> u8 {
+        match Command::ORDER.iter().position(|&c| c == self) {
+            Some(i) => 1 << i,
+            None => 0,
+        }
+    }
+}
+
+/// 1フレーム分の入力。複数のコマンドを同時に持てる
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Input(u8);
+
+impl Input {
+    pub const NONE: Input = Input(0);
+
+    pub fn contains(self, command: Command) -> bool {
+        self.0 & command.bit() != 0
+    }
+
+    pub fn insert(&mut self, command: Command) {
+        self.0 |= command.bit();
+    }
+
+    pub fn remove(&mut self, command: Command) {
+        self.0 &= !command.bit();
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Command::ORDER の順に返す
+    pub fn commands(self) -> impl Iterator<Item = Command> {
+        Command::ORDER
+            .into_iter()
+            .filter(move |&c| self.contains(c))
+    }
+}
+
+impl From<Command> for Input {
+    fn from(command: Command) -> Input {
+        Input(command.bit())
+    }
+}
+
+impl FromIterator<Command> for Input {
+    fn from_iter<T: IntoIterator<Item = Command>>(iter: T) -> Input {
+        let mut input = Input::NONE;
+        for command in iter {
+            input.insert(command);
         }
+        input
+    }
+}
+
+/// "None" または "Rotate+Left" のように + でつなげる
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "{:?}", Command::None);
+        }
+        let names: Vec<String> = self.commands().map(|c| format!("{:?}", c)).collect();
+        write!(f, "{}", names.join("+"))
+    }
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Input, String> {
+        str.split('+')
+            .map(|name| name.trim().parse::<Command>())
+            .collect()
     }
 }
 
@@ -59,51 +157,76 @@ pub enum State {
     PieceFalling, // 足場がなくなったピースが落下している状態
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Game {
+    pub rules: Rules,
+    pub seed: u64,
+    pub mode: Mode,
     pub rng: Option<StdRng>,
+    pub rng_draws: u64, // draw_color を呼んだ回数。スナップショットから乱数の状態を戻すのに使う
     pub is_over: bool,
     pub is_debug: bool,
     pub frame: i32,
     pub requested_sounds: Vec<&'static str>,
-    pub commands: Vec<Command>,    // リプレイデータから読み込んだコマンド
-    pub command_log: Option<File>, // コマンドログ
+    pub commands: Vec<Input>,     // リプレイデータから読み込んだ入力
+    pub history: Vec<Input>,      // 実際に使われた入力（リプレイ保存用）
+    pub checksums: Vec<Checksum>, // 着地ごとの状態。リプレイのずれを見つけるのに使う
     pub replay_loaded: bool,
+    pub settles: usize, // 盤面が落ち着いて次のピースが出た回数
     pub state: State,
-    pub field: [[i32; FIELD_W]; FIELD_H],
-    pub check_erase_result: [[bool; FIELD_W]; FIELD_H],
-    pub piece_falling: [[bool; FIELD_W]; FIELD_H],
+    pub paused: bool,         // 一時停止中はウェイトもタイマーも進めない
+    pub field: Vec<Vec<i32>>, // field[y][x]
+    pub check_erase_result: Vec<Vec<bool>>,
+    pub piece_falling: Vec<Vec<bool>>,
     pub current_x: usize,
     pub current_y: usize, // 3個つらなっている一番上のピースの座標
-    pub current: [i32; BLOCK_LEN],
-    pub next: [i32; BLOCK_LEN],
-    pub next_to_display: [i32; BLOCK_LEN],
+    pub current: Vec<i32>,
+    pub next: Vec<i32>,
+    pub next_to_display: Vec<i32>,
     pub erased_one_time: i32, // 連鎖も含めていっぺんに消した個数
     pub max_erased_at_one_time: i32,
     pub combo: i32, // 現在進行中のコンボ数
     pub total_erased: i32,
     pub max_combo: i32,
+    pub score: i32,
+    pub level: i32,
+    pub erase_lines: i32,          // check_erase でそろった列の数
+    pub erase_diagonal_lines: i32, // そのうち斜めの列の数
+    pub soft_drop: bool,           // 下キーで落下中
+    pub landed: bool,              // このフレームで着地した。残りの操作は次のピースに効かせない
+    pub magic_pending: bool,       // 次に出現させるブロックをマジックジュエルにする
     pub fall_wait: i32,
     pub spawn_wait: i32,
     pub flashing_wait: i32,
     pub piece_fall_wait: i32,
     pub piece_fall_offset: i32,
+    pub pressure_wait: i32, // プレッシャーモードで次にせり上がるまで
+    pub puzzle: Option<Puzzle>,
+    pub timer: Option<Timer>, // スプリントとウルトラのとき
 }
 
 impl Game {
-    pub fn new() -> Self {
+    /// 現在時刻をシードにして開始する
+    pub fn new(rules: Rules) -> Self {
         let now = time::SystemTime::now();
         let timestamp = now
             .duration_since(time::UNIX_EPOCH)
             .expect("SystemTime before UNIX EPOCH!")
             .as_secs();
-        let rng = StdRng::seed_from_u64(timestamp);
-        println!("random seed = {}", timestamp);
-        //let rng = StdRng::seed_from_u64(1706226338);
+        Game::with_seed(timestamp, rules)
+    }
+
+    /// 同じシードとルールなら常に同じ展開になる。ファイルの読み書きはしない
+    pub fn with_seed(seed: u64, rules: Rules) -> Self {
+        let rng = StdRng::seed_from_u64(seed);
 
         let mut game = Game {
+            field: vec![vec![EMPTY; rules.field_w]; rules.field_h],
+            check_erase_result: vec![vec![false; rules.field_w]; rules.field_h],
+            piece_falling: vec![vec![false; rules.field_w]; rules.field_h],
+            rules,
+            seed,
             rng: Some(rng),
-            command_log: Some(File::create("command.log").unwrap()),
             frame: -1,
             ..Default::default()
         };
@@ -111,7 +234,7 @@ impl Game {
         game.set_state(State::Controllable);
         game.spawn();
         game.spawn();
-        game.next_to_display = game.next;
+        game.next_to_display = game.next.clone();
 
         //game.current = [5, 5, 4];
         //game.next = [3, 1, 4];
@@ -134,72 +257,86 @@ impl Game {
         //    [4, 5, 1, 3, 2, 2],
         //];
 
-        game.load_replay("replay.dat");
-
         game
     }
 
+    /// モードを決めて開始する。with_seed の直後に呼ぶ
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        if mode == Mode::Pressure {
+            self.pressure_wait = mode::pressure_wait(self.level);
+        }
+        if mode.is_timed() {
+            self.timer = Some(Timer::new());
+        }
+        self
+    }
+
     pub fn toggle_debug(&mut self) {
         self.is_debug = !self.is_debug;
         println!("is_debug: {}", self.is_debug);
     }
 
-    pub fn load_replay(&mut self, filename: &str) {
-        if let Some(content) = std::fs::read_to_string(filename).ok() {
-            let mut commands = Vec::new();
-            for (_, line) in content.lines().enumerate() {
-                let command = Command::from_str(line);
-                commands.push(command);
-            }
-            self.replay_loaded = true;
-            self.commands = commands;
-        }
-    }
-
-    pub fn write_command_log(&mut self, command: Command) {
-        self.command_log
-            .as_ref()
-            .unwrap()
-            .write_all(format!("{:?}\n", command).as_bytes())
-            .ok();
-        self.command_log.as_ref().unwrap().flush().ok();
+    /// リプレイと同じシード・ルールで開始し、記録されたコマンドで進める
+    pub fn from_replay(replay: &Replay) -> Self {
+        let mut game = match &replay.stage {
+            Some(stage) => Game::from_stage(stage),
+            None => Game::with_seed(replay.seed, replay.rules.clone()).with_mode(replay.mode),
+        };
+        game.commands = replay.commands.clone();
+        game.replay_loaded = true;
+        game
     }
 
-    pub fn update(&mut self, mut command: Command) {
+    /// 1フレーム進める。input には Command も渡せる
+    pub fn update<I: Into<Input>>(&mut self, input: I) {
+        let mut input = input.into();
         self.frame += 1;
+        self.landed = false;
 
-        if self.replay_loaded {
-            if self.commands.len() > self.frame as usize {
-                command = self.commands[self.frame as usize];
-            }
-        } else {
-            self.write_command_log(command);
+        if self.is_over {
+            return;
         }
 
-        if self.is_over {
+        if self.replay_loaded && self.commands.len() > self.frame as usize {
+            input = self.commands[self.frame as usize];
+        }
+        self.history.push(input);
+
+        // 一時停止も入力として記録されるので、リプレイでも同じフレームで止まる
+        if input.contains(Command::Pause) {
+            self.paused = !self.paused;
+        }
+        if self.paused && !input.contains(Command::Step) {
+            return;
+        }
+        if !self.tick_timer() {
             return;
         }
 
+        if self.mode == Mode::Pressure {
+            // 連鎖の途中ならウェイトが 0 のまま、操作できるようになるまで待つ
+            wait!(self.pressure_wait, {
+                if self.state == State::Controllable {
+                    self.press();
+                }
+            });
+            if self.is_over {
+                return;
+            }
+        }
+
         match self.state {
             State::Controllable => {
                 wait!(self.fall_wait, {
                     self.fall();
                 });
 
-                match command {
-                    Command::Left => {
-                        self.move_block(-1);
-                    }
-                    Command::Right => {
-                        self.move_block(1);
-                    }
-                    Command::Down => {
-                        self.fall_wait = 0;
+                for command in input.commands() {
+                    if self.landed {
+                        break;
                     }
-                    Command::Rotate => {
-                        self.rotate();
-                    }
-                    Command::None => {}
+                    self.execute(command);
                 }
             }
             State::Flashing => {
@@ -219,6 +356,34 @@ impl Game {
                 }
             }
         }
+        self.check_timer();
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Left => {
+                self.move_block(-1);
+            }
+            Command::Right => {
+                self.move_block(1);
+            }
+            Command::SoftDrop => {
+                if self.fall_wait > self.rules.soft_drop_wait {
+                    self.fall_wait = self.rules.soft_drop_wait;
+                }
+                self.soft_drop = true;
+            }
+            Command::HardDrop => {
+                self.hard_drop();
+            }
+            Command::Rotate => {
+                self.rotate();
+            }
+            Command::RotateReverse => {
+                self.rotate_reverse();
+            }
+            Command::None | Command::Pause | Command::Step => {}
+        }
     }
 
     pub fn set_state(&mut self, new_state: State) {
@@ -229,12 +394,17 @@ impl Game {
                 self.combo = -1;
                 self.spawn();
                 self.check_gameover();
+                self.check_puzzle();
+                if self.frame >= 0 {
+                    self.settles += 1;
+                    self.checksums.push(Checksum::of(self));
+                }
             }
             State::Flashing => {
                 assert!(self.state == State::Controllable || self.state == State::PieceFalling);
 
                 self.combo += 1;
-                self.flashing_wait = FLASHING_WAIT;
+                self.flashing_wait = self.rules.flashing_wait;
             }
             State::PieceFalling => {
                 assert!(self.state == State::Flashing);
@@ -246,8 +416,8 @@ impl Game {
 
     pub fn check_piece_fall(&mut self) -> bool {
         let mut checked = false;
-        for y in (0..=FIELD_H - 2).rev() {
-            for x in 0..FIELD_W {
+        for y in (0..=self.rules.field_h - 2).rev() {
+            for x in 0..self.rules.field_w {
                 // 1マス下が空、または落下中ならそのマスも落下中とする
                 if self.field[y][x] != EMPTY
                     && (self.field[y + 1][x] == EMPTY || self.piece_falling[y + 1][x])
@@ -259,12 +429,12 @@ impl Game {
                 }
             }
         }
-        return checked;
+        checked
     }
 
     pub fn piece_fall(&mut self) -> bool {
         let mut should_continue = true;
-        self.piece_fall_offset += PIECE_FALL_SPEED;
+        self.piece_fall_offset += self.rules.piece_fall_speed;
         if self.piece_fall_offset >= CELL_SIZE {
             self.piece_fall_offset = 0;
             self.actually_piece_fall();
@@ -272,25 +442,28 @@ impl Game {
                 should_continue = false;
             }
         }
-        return should_continue;
+        should_continue
     }
 
     pub fn actually_piece_fall(&mut self) {
-        for y in (0..=FIELD_H - 2).rev() {
-            for x in 0..FIELD_W {
+        for y in (0..=self.rules.field_h - 2).rev() {
+            for x in 0..self.rules.field_w {
                 if self.piece_falling[y][x] {
                     self.field[y + 1][x] = self.field[y][x];
                     self.field[y][x] = EMPTY;
                 }
             }
         }
+        if let Some(puzzle) = &mut self.puzzle {
+            puzzle.on_fall(&self.piece_falling);
+        }
     }
 
     pub fn move_block(&mut self, dir: i32) {
         if dir == -1 && self.current_x == 0 {
             return;
         }
-        if dir == 1 && self.current_x == FIELD_W - 1 {
+        if dir == 1 && self.current_x == self.rules.field_w - 1 {
             return;
         }
         self.current_x = (self.current_x as i32 + dir) as usize;
@@ -300,59 +473,89 @@ impl Game {
     }
 
     pub fn rotate(&mut self) {
-        let tmp = self.current[BLOCK_LEN - 1];
-        for i in (1..=(BLOCK_LEN - 1)).rev() {
+        let block_len = self.rules.block_len;
+        let tmp = self.current[block_len - 1];
+        for i in (1..=(block_len - 1)).rev() {
             self.current[i] = self.current[i - 1];
         }
         self.current[0] = tmp;
     }
 
+    pub fn rotate_reverse(&mut self) {
+        let block_len = self.rules.block_len;
+        let tmp = self.current[0];
+        for i in 0..(block_len - 1) {
+            self.current[i] = self.current[i + 1];
+        }
+        self.current[block_len - 1] = tmp;
+    }
+
     pub fn fall(&mut self) {
         self.current_y += 1;
         if self.is_intersect() {
             self.current_y -= 1;
             self.settle();
-            if self.check_erase() {
+            if self.check_magic() || self.check_erase() {
                 self.set_state(State::Flashing);
             } else {
                 self.set_state(State::Controllable);
             }
+        } else if self.soft_drop {
+            self.score += score::SOFT_DROP_POINTS;
         }
+        self.soft_drop = false;
         if self.current_y == INVISIBLE_ROW_COUNT {
-            self.next_to_display = self.next;
+            self.next_to_display = self.next.clone();
         }
-        self.fall_wait = FALL_WAIT;
+        self.fall_wait = score::fall_wait(self.level, &self.rules);
     }
 
-    pub fn is_intersect(&self) -> bool {
-        let bottom_y = self.current_y + (BLOCK_LEN - 1);
-        if bottom_y == FIELD_H {
-            return true;
+    pub fn hard_drop(&mut self) {
+        let start_y = self.current_y;
+        loop {
+            self.current_y += 1;
+            if self.is_intersect() {
+                self.current_y -= 1;
+                break;
+            }
         }
-        if self.field[bottom_y][self.current_x] != EMPTY {
+        self.score += (self.current_y - start_y) as i32 * score::HARD_DROP_POINTS;
+        if start_y < INVISIBLE_ROW_COUNT && self.current_y >= INVISIBLE_ROW_COUNT {
+            self.next_to_display = self.next.clone();
+        }
+        self.soft_drop = false;
+        self.fall();
+    }
+
+    pub fn is_intersect(&self) -> bool {
+        let bottom_y = self.current_y + (self.rules.block_len - 1);
+        if bottom_y == self.rules.field_h {
             return true;
         }
-        return false;
+        self.field[bottom_y][self.current_x] != EMPTY
     }
 
     pub fn settle(&mut self) {
-        for i in 0..BLOCK_LEN {
+        for i in 0..self.rules.block_len {
             self.field[self.current_y + i][self.current_x] = self.current[i];
         }
+        self.landed = true;
         self.requested_sounds.push("hit.wav");
     }
 
     pub fn check_erase(&mut self) -> bool {
-        self.check_erase_result = Default::default();
+        self.check_erase_result = vec![vec![false; self.rules.field_w]; self.rules.field_h];
+        self.erase_lines = 0;
+        self.erase_diagonal_lines = 0;
         let mut checked = false;
 
-        for y in 0..FIELD_H {
-            for x in 0..FIELD_W {
-                if self.field[y][x] != EMPTY {
+        for y in 0..self.rules.field_h {
+            for x in 0..self.rules.field_w {
+                if self.field[y][x] != EMPTY && self.field[y][x] != MAGIC {
                     let dirs: [(i32, i32); 4] = [(1, 0), (1, 1), (0, 1), (1, -1)];
                     for dir in dirs {
                         let mut is_same = true;
-                        for i in 1..ERASE_LEN {
+                        for i in 1..self.rules.erase_len {
                             let x_ = x as i32 + dir.0 * i as i32;
                             let y_ = y as i32 + dir.1 * i as i32;
                             if !self.is_piece_exist(x_, y_)
@@ -363,7 +566,18 @@ impl Game {
                             }
                         }
                         if is_same {
-                            for i in 0..ERASE_LEN {
+                            // 1つ手前も同じ色なら、同じ列の続きなので数えない
+                            let x_ = x as i32 - dir.0;
+                            let y_ = y as i32 - dir.1;
+                            if !self.is_piece_exist(x_, y_)
+                                || self.field[y_ as usize][x_ as usize] != self.field[y][x]
+                            {
+                                self.erase_lines += 1;
+                                if dir.0 != 0 && dir.1 != 0 {
+                                    self.erase_diagonal_lines += 1;
+                                }
+                            }
+                            for i in 0..self.rules.erase_len {
                                 let x_ = x as i32 + dir.0 * i as i32;
                                 let y_ = y as i32 + dir.1 * i as i32;
                                 self.check_erase_result[y_ as usize][x_ as usize] = true;
@@ -374,21 +588,62 @@ impl Game {
                 }
             }
         }
-        return checked;
+        checked
+    }
+
+    /// 着地したのがマジックジュエルなら、その下の宝石と同じ色とマジックジュエル自身を消去対象にする
+    pub fn check_magic(&mut self) -> bool {
+        if self.current[0] != MAGIC {
+            return false;
+        }
+        self.check_erase_result = vec![vec![false; self.rules.field_w]; self.rules.field_h];
+        self.erase_lines = 0;
+        self.erase_diagonal_lines = 0;
+        let below_y = self.current_y + self.rules.block_len;
+        let color = if below_y < self.rules.field_h {
+            self.field[below_y][self.current_x]
+        } else {
+            EMPTY
+        };
+        for y in 0..self.rules.field_h {
+            for x in 0..self.rules.field_w {
+                if self.field[y][x] == MAGIC || (color != EMPTY && self.field[y][x] == color) {
+                    self.check_erase_result[y][x] = true;
+                }
+            }
+        }
+        true
     }
 
     pub fn actually_erase(&mut self) {
         let mut erased_count: i32 = 0;
-        for y in 0..FIELD_H {
-            for x in 0..FIELD_W {
+        for y in 0..self.rules.field_h {
+            for x in 0..self.rules.field_w {
                 if self.check_erase_result[y][x] {
+                    // マジックジュエル自身は消した個数に数えない
+                    if self.field[y][x] != MAGIC {
+                        erased_count += 1;
+                    }
                     self.field[y][x] = EMPTY;
-                    erased_count += 1;
                 }
             }
         }
         if erased_count > 0 {
+            self.score += score::erase_points(
+                erased_count,
+                self.erase_lines,
+                self.erase_diagonal_lines,
+                self.combo,
+                self.level,
+            );
+            let interval = self.rules.magic_interval;
+            if interval > 0
+                && (self.total_erased + erased_count) / interval > self.total_erased / interval
+            {
+                self.magic_pending = true;
+            }
             self.total_erased += erased_count;
+            self.level = score::level(self.total_erased, &self.rules);
             self.erased_one_time += erased_count;
             if self.max_erased_at_one_time < self.erased_one_time {
                 self.max_erased_at_one_time = self.erased_one_time;
@@ -398,35 +653,119 @@ impl Game {
             }
             self.requested_sounds.push("erase.wav");
         }
+        if let Some(puzzle) = &mut self.puzzle {
+            puzzle.on_erase(&self.check_erase_result);
+        }
     }
 
     pub fn is_piece_exist(&self, x: i32, y: i32) -> bool {
         0 <= x
-            && x < FIELD_W as i32
+            && x < self.rules.field_w as i32
             && 0 <= y
-            && y < FIELD_H as i32
+            && y < self.rules.field_h as i32
             && self.field[y as usize][x as usize] != EMPTY
     }
 
     pub fn spawn(&mut self) {
-        self.current = self.next;
-        self.current_x = FIELD_W / 2;
+        self.current = self.next.clone();
+        self.current_x = self.spawn_x();
         self.current_y = 0;
-        for i in 0..BLOCK_LEN {
-            self.next[i] = self.rng.as_mut().unwrap().gen_range(1..=COLOR_COUNT)
+        self.next = match &mut self.puzzle {
+            Some(puzzle) => puzzle.take_piece(self.rules.block_len),
+            None => (0..self.rules.block_len)
+                .map(|_| self.draw_color())
+                .collect(),
+        };
+        // 乱数の消費を変えないよう、色を決めてから置き換える
+        if self.magic_pending {
+            self.next = vec![MAGIC; self.rules.block_len];
+            self.magic_pending = false;
+        }
+    }
+
+    /// 乱数はすべてここから引く（引いた回数だけで状態を復元できるように）
+    pub fn draw_color(&mut self) -> i32 {
+        self.rng_draws += 1;
+        let rng = self.rng.as_mut().unwrap();
+        rng.gen_range(1..=self.rules.color_count)
+    }
+
+    /// seed から乱数を作り直し、draws 回引いた状態にする
+    pub fn restore_rng(&mut self, draws: u64) {
+        self.rng = Some(StdRng::seed_from_u64(self.seed));
+        self.rng_draws = 0;
+        for _ in 0..draws {
+            self.draw_color();
+        }
+    }
+
+    pub fn spawn_x(&self) -> usize {
+        self.rules.field_w / 2
+    }
+
+    /// 下に rows を足してフィールドを押し上げる。rows[0] が一番上の段になる。
+    /// 操作中のピースも同じだけ上げ、見えない段まで宝石が来るか、ピースが
+    /// フィールドの上端でつぶされるとゲームオーバー。そろった宝石は次の着地まで消さない
+    pub fn push_rows(&mut self, rows: &[Vec<i32>]) {
+        if self.is_over || rows.is_empty() {
+            return;
+        }
+        let mut is_over = false;
+        for row in rows {
+            assert_eq!(row.len(), self.rules.field_w, "row width must be field_w");
+            let top = self.field.remove(0);
+            if top.iter().any(|&v| v != EMPTY) {
+                is_over = true;
+            }
+            self.field.push(row.clone());
+            self.check_erase_result.remove(0);
+            self.check_erase_result
+                .push(vec![false; self.rules.field_w]);
+            self.piece_falling.remove(0);
+            self.piece_falling.push(vec![false; self.rules.field_w]);
+        }
+        if let Some(puzzle) = &mut self.puzzle {
+            puzzle.on_push(rows.len());
+        }
+        if self.field[..INVISIBLE_ROW_COUNT]
+            .iter()
+            .flatten()
+            .any(|&v| v != EMPTY)
+        {
+            is_over = true;
+        }
+        // 着地済みのピースはもうフィールドの一部なので、動かすのは操作中だけ
+        if self.state == State::Controllable {
+            self.current_y = self.current_y.saturating_sub(rows.len());
+            let bottom_y = (self.current_y + self.rules.block_len).min(self.rules.field_h);
+            if (self.current_y..bottom_y).any(|y| self.field[y][self.current_x] != EMPTY) {
+                is_over = true;
+            }
+        }
+        if is_over {
+            self.is_over = true;
+            self.requested_sounds.push("crash.wav");
         }
     }
 
+    /// プレッシャーモードで1段せり上げ、次までのウェイトを戻す
+    fn press(&mut self) {
+        let above = self.field[self.rules.field_h - 1].clone();
+        let row = garbage_row(&above, || self.draw_color());
+        self.push_rows(&[row]);
+        self.pressure_wait = mode::pressure_wait(self.level);
+    }
+
     pub fn check_gameover(&mut self) {
-        // 最上部の上（フィールドからはみ出た場所）に1個でも宝石が積みあがるか、右から3列目のみはみ出していなくても空いているマスが無くなるとゲームオーバー
+        // 最上部の上（フィールドからはみ出た場所）に1個でも宝石が積みあがるか、出現する列のみはみ出していなくても空いているマスが無くなるとゲームオーバー
         let mut is_over = false;
-        for x in 0..FIELD_W {
+        for x in 0..self.rules.field_w {
             if self.field[INVISIBLE_ROW_COUNT - 1][x] != EMPTY {
                 is_over = true;
                 break;
             }
         }
-        if self.field[INVISIBLE_ROW_COUNT][FIELD_W - 3] != EMPTY {
+        if self.field[INVISIBLE_ROW_COUNT][self.spawn_x()] != EMPTY {
             is_over = true;
         }
         if is_over {
@@ -436,6 +775,24 @@ impl Game {
     }
 }
 
+const GARBAGE_TRIES: usize = 16; // 2色しかないと避けきれないことがある
+
+/// せり上げる1段。左隣と真上（above）の色を避けて、入ったとたんにそろわないようにする
+pub fn garbage_row(above: &[i32], mut draw: impl FnMut() -> i32) -> Vec<i32> {
+    let mut row: Vec<i32> = Vec::with_capacity(above.len());
+    for x in 0..above.len() {
+        let mut color = draw();
+        for _ in 0..GARBAGE_TRIES {
+            if Some(&color) != row.last() && Some(&color) != above.get(x) {
+                break;
+            }
+            color = draw();
+        }
+        row.push(color);
+    }
+    row
+}
+
 #[cfg(test)]
 #[rustfmt::skip]
 mod tests {
@@ -443,25 +800,25 @@ mod tests {
 
     #[test]
     fn test_check_erase1() {
-        let mut game = Game::new();
-
-        game.field = [
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 1, 1, 1, 1],
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field = vec![
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 1, 1, 1, 1],
         ];
         game.check_erase();
         assert_eq!(
@@ -489,25 +846,25 @@ mod tests {
     
     #[test]
     fn test_check_erase2() {
-        let mut game = Game::new();
-
-        game.field = [
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 1],
-            [0, 0, 0, 0, 0, 1],
-            [0, 0, 0, 0, 0, 1],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field = vec![
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 1],
+            vec![0, 0, 0, 0, 0, 1],
+            vec![0, 0, 0, 0, 0, 1],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
         ];
         game.check_erase();
         assert_eq!(
@@ -535,25 +892,25 @@ mod tests {
     
     #[test]
     fn test_check_erase3() {
-        let mut game = Game::new();
-
-        game.field = [
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 1, 0, 0],
-            [0, 0, 0, 0, 1, 0],
-            [0, 0, 0, 0, 0, 1],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field = vec![
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 1, 0, 0],
+            vec![0, 0, 0, 0, 1, 0],
+            vec![0, 0, 0, 0, 0, 1],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
         ];
         game.check_erase();
         assert_eq!(
@@ -581,25 +938,25 @@ mod tests {
 
     #[test]
     fn test_check_erase4() {
-        let mut game = Game::new();
-
-        game.field = [
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 1, 0, 0, 0],
-            [0, 0, 0, 1, 0, 0],
-            [0, 0, 0, 0, 1, 0],
-            [0, 0, 1, 1, 1, 1],
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field = vec![
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 1, 0, 0, 0],
+            vec![0, 0, 0, 1, 0, 0],
+            vec![0, 0, 0, 0, 1, 0],
+            vec![0, 0, 1, 1, 1, 1],
         ];
         game.check_erase();
         assert_eq!(
@@ -627,25 +984,25 @@ mod tests {
 
     #[test]
     fn test_check_erase5() {
-        let mut game = Game::new();
-
-        game.field = [
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 1, 0],
-            [0, 0, 0, 1, 0, 0],
-            [0, 0, 1, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
-            [0, 0, 0, 0, 0, 0],
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field = vec![
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 1, 0],
+            vec![0, 0, 0, 1, 0, 0],
+            vec![0, 0, 1, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0],
         ];
         game.check_erase();
         assert_eq!(
@@ -670,4 +1027,247 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_check_erase_len4() {
+        let mut game = Game::with_seed(0, Rules::preset("four").unwrap());
+
+        game.field[15] = vec![0, 1, 1, 1, 0, 0];
+        assert!(!game.check_erase());
+        game.field[15] = vec![0, 1, 1, 1, 1, 0];
+        assert!(game.check_erase());
+        assert_eq!(game.check_erase_result[15], [false, true, true, true, true, false]);
+    }
+
+    #[test]
+    fn test_with_seed() {
+        let mut game1 = Game::with_seed(1706226338, Rules::default());
+        let mut game2 = Game::with_seed(1706226338, Rules::default());
+        for _ in 0..2000 {
+            game1.update(Command::SoftDrop);
+            game2.update(Command::SoftDrop);
+        }
+        assert_eq!(game1.field, game2.field);
+        assert_eq!(game1.next, game2.next);
+        assert!(!game1.replay_loaded);
+    }
+
+    #[test]
+    fn test_erase_lines() {
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field[12] = vec![0, 0, 1, 0, 0, 0];
+        game.field[13] = vec![0, 0, 0, 1, 0, 0];
+        game.field[14] = vec![0, 0, 0, 0, 1, 0];
+        game.field[15] = vec![0, 0, 1, 1, 1, 1];
+        assert!(game.check_erase());
+        assert_eq!(game.erase_lines, 2);
+        assert_eq!(game.erase_diagonal_lines, 1);
+
+        game.combo = 0;
+        game.actually_erase();
+        assert_eq!(game.total_erased, 7);
+        assert_eq!(game.score, score::erase_points(7, 2, 1, 0, 0));
+    }
+
+    #[test]
+    fn test_magic() {
+        let mut game = Game::with_seed(0, Rules::default());
+
+        game.field[13] = vec![0, 2, 0, 0, 0, 0];
+        game.field[14] = vec![0, 1, 0, 3, 0, 2];
+        game.field[15] = vec![2, 1, 0, 2, 0, 1];
+        game.current = vec![MAGIC; 3];
+        game.current_x = 3;
+        game.current_y = 11;
+        game.fall();
+        assert_eq!(game.state, State::Flashing);
+        game.actually_erase();
+        assert_eq!(game.total_erased, 1);
+        assert_eq!(game.field[12], [0, 0, 0, 0, 0, 0]);
+        assert_eq!(game.field[13], [0, 2, 0, 0, 0, 0]);
+        assert_eq!(game.field[14], [0, 1, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_magic_spawn() {
+        let mut game = Game::with_seed(0, Rules { magic_interval: 3, ..Rules::default() });
+
+        game.field[15] = vec![0, 1, 1, 1, 0, 0];
+        game.check_erase();
+        game.actually_erase();
+        assert!(game.magic_pending);
+        game.spawn();
+        assert_eq!(game.next, [MAGIC; 3]);
+        assert!(!game.magic_pending);
+    }
+
+    #[test]
+    fn test_hard_drop() {
+        let mut game = Game::with_seed(0, Rules::default());
+        game.update(Command::None);
+        game.field[15] = vec![0, 0, 0, 1, 0, 0];
+        let current = game.current.clone();
+        game.update(Command::HardDrop);
+        assert_eq!(game.field[12][3], current[0]);
+        assert_eq!(game.field[14][3], current[2]);
+        assert_eq!(game.current_y, 0);
+        assert_eq!(game.score, 11 * score::HARD_DROP_POINTS);
+
+        // 重力で着地したフレームのハードドロップは、出てきたばかりのピースを落とさない
+        let mut game = Game::with_seed(0, Rules::default());
+        game.update(Command::None);
+        game.field[15] = vec![0, 0, 0, 1, 0, 0];
+        game.current_y = 12;
+        game.fall_wait = 1;
+        let current = game.current.clone();
+        let next = game.next.clone();
+        game.update(Command::HardDrop);
+        assert_eq!(game.field[14][3], current[2]);
+        assert_eq!(game.current, next);
+        assert_eq!(game.current_y, 0);
+        assert_eq!(game.score, 0);
+        assert_eq!(game.field[11][3], EMPTY);
+    }
+
+    #[test]
+    fn test_soft_drop() {
+        let rules = Rules { soft_drop_wait: 2, ..Rules::default() };
+        let mut game = Game::with_seed(0, rules);
+        game.update(Command::None);
+        let y = game.current_y;
+        // 1フレーム目でウェイトが縮み、以降は2フレームごとに1マス
+        for _ in 0..9 {
+            game.update(Command::SoftDrop);
+        }
+        assert_eq!(game.current_y, y + 4);
+        assert_eq!(game.score, 4 * score::SOFT_DROP_POINTS);
+    }
+
+    #[test]
+    fn test_rotate() {
+        for block_len in 1..=5 {
+            let rules = Rules { block_len, ..Rules::default() };
+            let mut game = Game::with_seed(0, rules);
+            let original: Vec<i32> = (1..=block_len as i32).collect();
+            game.current = original.clone();
+
+            game.update(Command::Rotate);
+            let mut expected = original.clone();
+            expected.rotate_right(1);
+            assert_eq!(game.current, expected);
+
+            game.update(Command::RotateReverse);
+            assert_eq!(game.current, original);
+
+            game.update(Command::RotateReverse);
+            let mut expected = original.clone();
+            expected.rotate_left(1);
+            assert_eq!(game.current, expected);
+
+            for _ in 1..block_len {
+                game.update(Command::RotateReverse);
+            }
+            assert_eq!(game.current, original);
+        }
+    }
+    #[test]
+    fn test_input() {
+        let input: Input = "Left+Rotate".parse().unwrap();
+        assert!(input.contains(Command::Left) && input.contains(Command::Rotate));
+        assert_eq!(input.to_string(), "Rotate+Left");
+        assert_eq!(Input::NONE.to_string(), "None");
+        assert_eq!("None".parse(), Ok(Input::NONE));
+        assert!("Left+Jump".parse::<Input>().is_err());
+
+        // 同じフレームで回転と移動
+        let mut game = Game::with_seed(0, Rules::default());
+        game.update(Command::None);
+        let x = game.current_x;
+        let current = game.current.clone();
+        game.update(input);
+        let mut expected = current.clone();
+        expected.rotate_right(1);
+        assert_eq!(game.current, expected);
+        assert_eq!(game.current_x, x - 1);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut game = Game::with_seed(0, Rules::default());
+        game.update(Command::None);
+        let (y, fall_wait) = (game.current_y, game.fall_wait);
+
+        game.update(Command::Pause);
+        for _ in 0..100 {
+            game.update(Command::Left);
+        }
+        assert!(game.paused);
+        assert_eq!((game.current_x, game.current_y), (game.spawn_x(), y));
+        assert_eq!(game.fall_wait, fall_wait);
+
+        let step: Input = [Command::Step, Command::Left].into_iter().collect();
+        game.update(step);
+        assert!(game.paused);
+        assert_eq!(game.current_x, game.spawn_x() - 1);
+        assert_eq!(game.fall_wait, fall_wait - 1);
+
+        game.update(Command::Pause);
+        assert!(!game.paused);
+        assert_eq!(game.fall_wait, fall_wait - 2);
+        assert_eq!(game.history.len(), 104);
+    }
+
+    #[test]
+    fn test_push_rows() {
+        let mut game = Game::with_seed(0, Rules::default());
+        game.field[15] = vec![1, 2, 0, 0, 0, 0];
+        game.current_y = 8;
+        game.push_rows(&[vec![3; 6], vec![4; 6]]);
+        assert_eq!(game.field[13], [1, 2, 0, 0, 0, 0]);
+        assert_eq!(game.field[14], [3, 3, 3, 3, 3, 3]);
+        assert_eq!(game.field[15], [4, 4, 4, 4, 4, 4]);
+        assert_eq!(game.current_y, 6);
+        assert!(!game.is_over);
+
+        // 見えない段まで押し上げられたら負け
+        game.push_rows(&vec![vec![5; 6]; 10]);
+        assert!(!game.is_over);
+        game.push_rows(&[vec![5; 6]]);
+        assert!(game.is_over);
+
+        // 操作中のピースが上端でつぶされても負け
+        let mut game = Game::with_seed(0, Rules::default());
+        game.field[3] = vec![0, 0, 0, 1, 0, 0];
+        game.current_y = 0;
+        game.push_rows(&[vec![2; 6]]);
+        assert!(game.is_over);
+
+        // 左隣と同じ色、真上と同じ色は引き直す
+        let mut draws = [1, 2, 2, 5, 3, 3, 4].into_iter();
+        let row = garbage_row(&[1, 5, 3], || draws.next().unwrap());
+        assert_eq!(row, [2, 3, 4]);
+        assert_eq!(draws.next(), None);
+        // 引き直しが尽きたら最後に引いた色のまま
+        assert_eq!(garbage_row(&[2, 2], || 2), [2, 2]);
+    }
+
+    #[test]
+    fn test_pressure() {
+        let mut game = Game::with_seed(0, Rules::default()).with_mode(Mode::Pressure);
+        assert_eq!(game.pressure_wait, mode::PRESSURE_WAIT);
+        for _ in 0..mode::PRESSURE_WAIT {
+            game.update(Command::None);
+        }
+        assert!(game.field[15].iter().all(|&v| v != EMPTY));
+        assert_eq!(game.pressure_wait, mode::pressure_wait(game.level));
+        assert!(game.field[15].windows(2).all(|pair| pair[0] != pair[1]));
+
+        // エンドレスではせり上がらない
+        let mut game = Game::with_seed(0, Rules::default());
+        for _ in 0..mode::PRESSURE_WAIT {
+            game.update(Command::None);
+        }
+        assert!(game.field[15].iter().all(|&v| v == EMPTY));
+    }
 }
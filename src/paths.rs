@@ -0,0 +1,41 @@
+//! Where the game keeps its files between runs.
+//!
+//! Follows the usual place of each platform without extra dependencies:
+//! `$XDG_DATA_HOME` (or `~/.local/share`) and `$XDG_CONFIG_HOME` (or
+//! `~/.config`) on Linux, `~/Library/Application Support` on macOS and
+//! `%APPDATA%` on Windows.
+
+use std::env;
+use std::path::PathBuf;
+
+pub const APP_NAME: &str = "rust-columns";
+
+/// ハイスコアと保存したリプレイを置くディレクトリ。ホームが分からなければ None
+pub fn data_dir() -> Option<PathBuf> {
+    app_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// 設定ファイルを置くディレクトリ
+pub fn config_dir() -> Option<PathBuf> {
+    app_dir("XDG_CONFIG_HOME", ".config")
+}
+
+fn app_dir(xdg_var: &str, xdg_default: &str) -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library/Application Support"))
+    } else {
+        env::var_os(xdg_var)
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| home().map(|home| home.join(xdg_default)))
+    };
+    base.map(|base| base.join(APP_NAME))
+}
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
@@ -0,0 +1,563 @@
+//! Flash Columns style puzzles.
+//!
+//! A stage starts from a pre-filled field and gives a fixed sequence of
+//! pieces. It is cleared by reaching its goal before the pieces run out:
+//! erasing the flashing target jewel, erasing a number of jewels, or making a
+//! chain of a given length. Stages are text files in [`STAGE_DIR`], named by
+//! their id; field rows and pieces are keyed by their index, top to bottom.
+//!
+//! ```text
+//! name = "FIRST FLASH"
+//! goal = "target 2 15"   # or "jewels 12", "chain 3"
+//!
+//! [rules]
+//! base = "classic"       # any rule key can follow
+//!
+//! [field]
+//! 14 = "0 0 4 1 0 0"
+//! 15 = "1 2 3 5 2 6"
+//!
+//! [pieces]
+//! 0 = "4 3 3"
+//! ```
+//!
+//! Replays and snapshots of a puzzle carry a copy of the stage, so they play
+//! back even if the stage file changes. The best clear time of each stage is
+//! kept in [`RECORDS_FILE`] in the data directory.
+
+use crate::config;
+use crate::mode::Mode;
+use crate::model::{Game, State, EMPTY, INVISIBLE_ROW_COUNT, MAGIC};
+use crate::rules::Rules;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{fmt, str::FromStr};
+
+pub const STAGE_DIR: &str = "resources/stages";
+pub const RECORDS_FILE: &str = "puzzles.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Goal {
+    Target(usize, usize), // (x, y) の宝石を消す
+    Jewels(i32),          // 合わせてこの個数を消す
+    Chain(i32),           // この連鎖数を出す（2以上）
+}
+
+impl fmt::Display for Goal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Goal::Target(x, y) => write!(f, "target {} {}", x, y),
+            Goal::Jewels(count) => write!(f, "jewels {}", count),
+            Goal::Chain(count) => write!(f, "chain {}", count),
+        }
+    }
+}
+
+impl FromStr for Goal {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Goal, String> {
+        let words: Vec<&str> = str.split_whitespace().collect();
+        match words.as_slice() {
+            ["target", x, y] => Ok(Goal::Target(
+                config::parse_value("goal", x)?,
+                config::parse_value("goal", y)?,
+            )),
+            ["jewels", count] => Ok(Goal::Jewels(config::parse_value("goal", count)?)),
+            ["chain", count] => Ok(Goal::Chain(config::parse_value("goal", count)?)),
+            _ => Err(format!("unknown goal: {}", str)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub id: String, // ファイル名から拡張子を除いたもの
+    pub name: String,
+    pub goal: Goal,
+    pub rules: Rules,
+    pub field: Vec<Vec<i32>>, // field[y][x]
+    pub pieces: Vec<Vec<i32>>,
+}
+
+fn join(values: &[i32]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(" ")
+}
+
+fn split(key: &str, value: &str) -> Result<Vec<i32>, String> {
+    value
+        .split_whitespace()
+        .map(|v| config::parse_value(key, v))
+        .collect()
+}
+
+impl Stage {
+    pub fn load(path: &Path) -> Result<Stage, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let id = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Stage::parse(&id, &text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// ステージの id またはファイルパスから読み込む
+    pub fn from_id_or_path(name: &str) -> Result<Stage, String> {
+        let path = Path::new(name);
+        if path.is_file() {
+            Stage::load(path)
+        } else {
+            Stage::load(&Path::new(STAGE_DIR).join(format!("{}.toml", name)))
+        }
+    }
+
+    pub fn parse(id: &str, text: &str) -> Result<Stage, String> {
+        let entries = config::parse(text)?;
+        let mut rules = Rules::default();
+        if let Some((_, base)) = entries.iter().find(|(key, _)| key == "rules.base") {
+            rules = Rules::preset(base).ok_or(format!("unknown preset: {}", base))?;
+        }
+        for (key, value) in &entries {
+            if let Some(key) = key.strip_prefix("rules.") {
+                if key != "base" {
+                    rules.set(key, value)?;
+                }
+            }
+        }
+        rules.validate()?;
+        Stage::from_entries(&entries, "", id, rules)
+    }
+
+    /// ステージファイルの形式で書き出す
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "name = {}\ngoal = {}\n\n[rules]\n",
+            config::quote(&self.name),
+            config::quote(&self.goal.to_string())
+        );
+        text += &self.rules.to_config();
+        text += &self.grids("");
+        text
+    }
+
+    /// リプレイやスナップショットに埋め込む形。ルールはそちらのものを使う
+    pub(crate) fn to_section(&self, section: &str) -> String {
+        let mut text = format!(
+            "\n[{}]\nid = {}\nname = {}\ngoal = {}\n",
+            section,
+            config::quote(&self.id),
+            config::quote(&self.name),
+            config::quote(&self.goal.to_string())
+        );
+        text += &self.grids(&format!("{}.", section));
+        text
+    }
+
+    fn grids(&self, prefix: &str) -> String {
+        let mut text = format!("\n[{}field]\n", prefix);
+        for (y, row) in self.field.iter().enumerate() {
+            if row.iter().any(|&v| v != EMPTY) {
+                text += &format!("{} = {}\n", y, config::quote(&join(row)));
+            }
+        }
+        text += &format!("\n[{}pieces]\n", prefix);
+        for (i, piece) in self.pieces.iter().enumerate() {
+            text += &format!("{} = {}\n", i, config::quote(&join(piece)));
+        }
+        text
+    }
+
+    /// section の中の項目からステージを作る。section が空ならステージファイル
+    pub(crate) fn from_entries(
+        entries: &[(String, String)],
+        section: &str,
+        id: &str,
+        rules: Rules,
+    ) -> Result<Stage, String> {
+        let prefix = if section.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", section)
+        };
+        let get = |key: &str| {
+            let key = format!("{}{}", prefix, key);
+            entries
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.as_str())
+                .ok_or(format!("missing `{}`", key))
+        };
+
+        let mut field = vec![vec![EMPTY; rules.field_w]; rules.field_h];
+        let mut pieces = BTreeMap::new();
+        for (key, value) in entries {
+            let Some(key) = key.strip_prefix(&prefix) else {
+                continue;
+            };
+            if let Some(y) = key.strip_prefix("field.") {
+                let y: usize = config::parse_value(key, y)?;
+                let row = split(key, value)?;
+                if y >= rules.field_h || row.len() != rules.field_w {
+                    return Err(format!("`{}` does not fit the field", key));
+                }
+                field[y] = row;
+            } else if let Some(i) = key.strip_prefix("pieces.") {
+                pieces.insert(config::parse_value::<usize>(key, i)?, split(key, value)?);
+            }
+        }
+        // 番号は 0 から抜けなく
+        if pieces.keys().enumerate().any(|(i, &key)| i != key) {
+            return Err("pieces must be numbered from 0 without gaps".to_string());
+        }
+
+        let stage = Stage {
+            id: get("id").unwrap_or(id).to_string(),
+            name: get("name")?.to_string(),
+            goal: get("goal")?.parse()?,
+            rules,
+            field,
+            pieces: pieces.into_values().collect(),
+        };
+        stage.validate()?;
+        Ok(stage)
+    }
+
+    /// 遊べる盤面か確かめる。最初からそろっていたり、宝石が浮いていたりしてはいけない
+    pub fn validate(&self) -> Result<(), String> {
+        let rules = &self.rules;
+        let is_color = |v: i32| (1..=rules.color_count).contains(&v) || v == MAGIC;
+        match self.goal {
+            Goal::Target(x, y)
+                if x >= rules.field_w || y >= rules.field_h || !is_color(self.field[y][x]) =>
+            {
+                return Err(format!("no jewel at the target: {}", self.goal));
+            }
+            Goal::Jewels(count) if count < 1 => {
+                return Err(format!("goal must be at least 1 jewel: {}", count));
+            }
+            Goal::Chain(count) if count < 2 => {
+                return Err(format!("goal must be a chain of at least 2: {}", count));
+            }
+            _ => {}
+        }
+        if self.pieces.is_empty() {
+            return Err("no pieces".to_string());
+        }
+        for (i, piece) in self.pieces.iter().enumerate() {
+            if piece.len() != rules.block_len || !piece.iter().all(|&v| is_color(v)) {
+                return Err(format!("bad piece {}: {}", i, join(piece)));
+            }
+        }
+        for (y, row) in self.field.iter().enumerate() {
+            for (x, &v) in row.iter().enumerate() {
+                if v == EMPTY {
+                    continue;
+                }
+                if !is_color(v) {
+                    return Err(format!("bad jewel at {} {}: {}", x, y, v));
+                }
+                if y < INVISIBLE_ROW_COUNT {
+                    return Err(format!("jewel above the field at {} {}", x, y));
+                }
+                if y + 1 < rules.field_h && self.field[y + 1][x] == EMPTY {
+                    return Err(format!("floating jewel at {} {}", x, y));
+                }
+            }
+        }
+        let mut game = Game::with_seed(0, rules.clone());
+        game.field = self.field.clone();
+        if game.field[INVISIBLE_ROW_COUNT][game.spawn_x()] != EMPTY {
+            return Err("the spawn column is full".to_string());
+        }
+        if game.check_erase() {
+            return Err("jewels are already lined up".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// STAGE_DIR などにあるステージを id の順にすべて読み込む
+pub fn load_stages(dir: &Path) -> Result<Vec<Stage>, String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+    paths.iter().map(|path| Stage::load(path)).collect()
+}
+
+/// 遊んでいるステージの進み具合
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Puzzle {
+    pub stage: Stage,
+    pub next_piece: usize,              // 次に出す pieces の添字
+    pub target: Option<(usize, usize)>, // 目標の宝石の今の位置。消したら None
+    pub cleared: bool,
+}
+
+impl Puzzle {
+    pub fn new(stage: Stage) -> Self {
+        let target = match stage.goal {
+            Goal::Target(x, y) => Some((x, y)),
+            _ => None,
+        };
+        Puzzle {
+            stage,
+            next_piece: 0,
+            target,
+            cleared: false,
+        }
+    }
+
+    /// 次のピース。使い切ったら空のピース
+    pub(crate) fn take_piece(&mut self, block_len: usize) -> Vec<i32> {
+        match self.stage.pieces.get(self.next_piece) {
+            Some(piece) => {
+                self.next_piece += 1;
+                piece.clone()
+            }
+            None => vec![EMPTY; block_len],
+        }
+    }
+
+    pub(crate) fn on_erase(&mut self, erased: &[Vec<bool>]) {
+        if let Some((x, y)) = self.target {
+            if erased[y][x] {
+                self.target = None;
+            }
+        }
+    }
+
+    /// 落下中の宝石が1マス落ちたとき。目標もいっしょに落ちる
+    pub(crate) fn on_fall(&mut self, falling: &[Vec<bool>]) {
+        if let Some((x, y)) = &mut self.target {
+            if falling[*y][*x] {
+                *y += 1;
+            }
+        }
+    }
+
+    pub(crate) fn on_push(&mut self, rows: usize) {
+        if let Some((_, y)) = &mut self.target {
+            *y = y.saturating_sub(rows);
+        }
+    }
+}
+
+impl Game {
+    /// ステージの盤面とピースで始める。乱数は使わない
+    pub fn from_stage(stage: &Stage) -> Self {
+        let mut game = Game::with_seed(0, stage.rules.clone()).with_mode(Mode::Puzzle);
+        game.field = stage.field.clone();
+        game.puzzle = Some(Puzzle::new(stage.clone()));
+        game.spawn();
+        game.spawn();
+        game.next_to_display = game.next.clone();
+        game
+    }
+
+    /// 操作中と次のものも含めた、残りのピースの数
+    pub fn pieces_left(&self) -> usize {
+        let Some(puzzle) = &self.puzzle else {
+            return 0;
+        };
+        let is_shown = |piece: &Vec<i32>| piece.iter().any(|&v| v != EMPTY);
+        // 着地したあとの current はもうフィールドの一部
+        let current = self.state == State::Controllable && is_shown(&self.current);
+        let shown = current as usize + is_shown(&self.next) as usize;
+        puzzle.stage.pieces.len() - puzzle.next_piece + shown
+    }
+
+    /// 目標を達成したか、ピースを使い切ったら終わる。連鎖が終わったところで呼ぶ
+    pub(crate) fn check_puzzle(&mut self) {
+        let Some(puzzle) = &mut self.puzzle else {
+            return;
+        };
+        puzzle.cleared = match puzzle.stage.goal {
+            Goal::Target(..) => puzzle.target.is_none(),
+            Goal::Jewels(count) => self.total_erased >= count,
+            Goal::Chain(count) => self.max_combo + 1 >= count,
+        };
+        if puzzle.cleared {
+            self.is_over = true;
+        } else if !self.is_over && self.current.iter().all(|&v| v == EMPTY) {
+            self.is_over = true;
+            self.requested_sounds.push("crash.wav");
+        }
+    }
+}
+
+/// ステージごとのいちばん速いクリアタイム（フレーム数）
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Records {
+    pub best: BTreeMap<String, i32>,
+}
+
+impl Records {
+    pub fn parse(text: &str) -> Result<Records, String> {
+        let mut records = Records::default();
+        for (key, value) in config::parse(text)? {
+            match key.split_once('.') {
+                Some((id, "frames")) => {
+                    records
+                        .best
+                        .insert(id.to_string(), config::parse_value(&key, &value)?);
+                }
+                _ => return Err(format!("unknown key: {}", key)),
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn to_text(&self) -> String {
+        self.best
+            .iter()
+            .map(|(id, frames)| format!("[{}]\nframes = {}\n\n", id, frames))
+            .collect()
+    }
+
+    /// まだファイルが無ければ空
+    pub fn load(dir: &Path) -> Result<Records, String> {
+        let path = dir.join(RECORDS_FILE);
+        if !path.exists() {
+            return Ok(Records::default());
+        }
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Records::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        let path = dir.join(RECORDS_FILE);
+        std::fs::write(&path, self.to_text())
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    /// 記録を更新したら true
+    pub fn record(&mut self, id: &str, frames: i32) -> bool {
+        if self.best.get(id).is_some_and(|&best| best <= frames) {
+            return false;
+        }
+        self.best.insert(id.to_string(), frames);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Command;
+    use crate::replay::Replay;
+
+    fn stages() -> Vec<Stage> {
+        load_stages(&Path::new(env!("CARGO_MANIFEST_DIR")).join(STAGE_DIR)).unwrap()
+    }
+
+    fn play(game: &mut Game, commands: &[Command]) {
+        for &command in commands {
+            game.update(command);
+        }
+        while !game.is_over {
+            game.update(Command::None);
+        }
+    }
+
+    #[test]
+    fn test_stages() {
+        let stages = stages();
+        assert!(stages.len() >= 5);
+        for stage in &stages {
+            assert_eq!(
+                Stage::parse(&stage.id, &stage.to_text()).as_ref(),
+                Ok(stage)
+            );
+        }
+        assert_eq!(stages[0].id, "01");
+        assert_eq!(stages[0].goal, Goal::Target(2, 15));
+        assert_eq!("chain 3".parse(), Ok(Goal::Chain(3)));
+        assert!("chain".parse::<Goal>().is_err());
+    }
+
+    #[test]
+    fn test_clear() {
+        let stage = &stages()[0];
+        let mut game = Game::from_stage(stage);
+        assert_eq!(game.current, stage.pieces[0]);
+        assert_eq!(game.pieces_left(), stage.pieces.len());
+        play(&mut game, &[Command::Left, Command::HardDrop]);
+        assert!(game.puzzle.as_ref().unwrap().cleared);
+        assert_eq!(game.puzzle.as_ref().unwrap().target, None);
+
+        // リプレイにはステージが入っていて、同じように再生できる
+        let replay = Replay::parse(&Replay::from_game(&game, "").to_text()).unwrap();
+        assert_eq!(replay.stage.as_ref(), Some(stage));
+        assert_eq!(replay.find_desync(), None);
+
+        // 途中で保存しても続きから遊べる
+        let mut game = Game::from_stage(stage);
+        game.update(Command::Left);
+        let mut game = Game::from_snapshot(&game.to_snapshot()).unwrap();
+        play(&mut game, &[Command::HardDrop]);
+        assert!(game.puzzle.as_ref().unwrap().cleared);
+    }
+
+    #[test]
+    fn test_fail() {
+        // 動かさずに落とし続けるとピースを使い切る
+        let stage = &stages()[0];
+        let mut game = Game::from_stage(stage);
+        let drops = vec![Command::HardDrop; stage.pieces.len()];
+        play(&mut game, &drops);
+        assert!(!game.puzzle.as_ref().unwrap().cleared);
+        assert_eq!(game.pieces_left(), 0);
+        assert!(game.requested_sounds.contains(&"crash.wav"));
+    }
+
+    #[test]
+    fn test_target_falls() {
+        let mut puzzle = Puzzle::new(stages()[0].clone());
+        let mut falling = vec![vec![false; 6]; 16];
+        puzzle.target = Some((2, 13));
+        puzzle.on_fall(&falling);
+        assert_eq!(puzzle.target, Some((2, 13)));
+        falling[13][2] = true;
+        puzzle.on_fall(&falling);
+        assert_eq!(puzzle.target, Some((2, 14)));
+        puzzle.on_push(3);
+        assert_eq!(puzzle.target, Some((2, 11)));
+    }
+
+    #[test]
+    fn test_reject() {
+        let text = stages()[0].to_text();
+        let broken = [
+            text.replace("target 2 15", "target 2 14"),
+            text.replace("target 2 15", "chain 1"),
+            text.replace("\n15 = \"1 2 3 5 2 6\"", "\n14 = \"1 2 3 5 2 6\""),
+            text.replace("\"1 2 3 5 2 6\"", "\"1 2 3 3 3 6\""),
+            text.replace("\n1 = ", "\n5 = "),
+            text.replace("\"4 3 3\"", "\"4 3\""),
+        ];
+        for text in broken {
+            assert!(Stage::parse("01", &text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_records() {
+        let mut records = Records::default();
+        assert!(records.record("01", 300));
+        assert!(!records.record("01", 400));
+        assert!(records.record("01", 200));
+        assert!(records.record("02", 900));
+        assert_eq!(Records::parse(&records.to_text()), Ok(records.clone()));
+        assert_eq!(records.best.get("01"), Some(&200));
+        assert!(Records::parse("[01]\nscore = 3\n").is_err());
+    }
+}
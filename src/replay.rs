@@ -0,0 +1,357 @@
+//! Replay files.
+//!
+//! A replay is a small text file: a `key = value` header (format version,
+//! game version, seed, game mode, rules, metadata and the final result)
+//! followed by the input stream, run-length encoded one `count Input` pair per
+//! line. An input is `None` or the commands of the frame joined with `+`. The
+//! checksums taken whenever the field settled come last (see
+//! [`crate::desync`]). Puzzle replays also carry the stage in `[stage]`
+//! sections (see [`crate::puzzle`]).
+//!
+//! ```text
+//! format_version = 4
+//! game_version = "0.1.0"
+//! seed = 1706226338
+//! mode = "endless"
+//! player = "aoyama"
+//! date = 1706226400
+//!
+//! [rules]
+//! field_w = 6
+//! ...
+//!
+//! [result]
+//! score = 1230
+//! ...
+//!
+//! [commands]
+//! 29 None
+//! 1 Left
+//! 2 Rotate+Left
+//!
+//! [checksums]
+//! 45 9f3a1c0e7b2d4410 000000/000000/.../001000/005000
+//! ```
+
+use crate::config;
+use crate::desync::{decode_checksums, encode_checksums, Checksum, Desync};
+use crate::mode::Mode;
+use crate::model::{Game, Input, FPS};
+use crate::puzzle::Stage;
+use crate::rules::Rules;
+use std::path::Path;
+use std::time;
+
+pub const FORMAT_VERSION: u32 = 4;
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// 入力の長さが分からないときの上限（1日分）
+pub(crate) const MAX_FRAMES: usize = FPS as usize * 60 * 60 * 24;
+
+const COMMANDS_HEADER: &str = "[commands]";
+const CHECKSUMS_HEADER: &str = "[checksums]";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayResult {
+    pub frames: i32,
+    pub score: i32,
+    pub total_erased: i32,
+    pub max_combo: i32,
+    pub max_erased_at_one_time: i32,
+    pub time: i32, // スプリントを完走したタイム（フレーム）。それ以外は 0
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    pub game_version: String,
+    pub seed: u64,
+    pub mode: Mode,
+    pub rules: Rules,
+    pub stage: Option<Stage>, // パズルモードのときだけ
+    pub player: String,
+    pub date: u64, // UNIX時間（秒）
+    pub result: ReplayResult,
+    pub commands: Vec<Input>, // 1フレームに1個
+    pub checksums: Vec<Checksum>,
+}
+
+impl Replay {
+    pub fn from_game(game: &Game, player: &str) -> Replay {
+        let date = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Replay {
+            game_version: GAME_VERSION.to_string(),
+            seed: game.seed,
+            mode: game.mode,
+            rules: game.rules.clone(),
+            stage: game.puzzle.as_ref().map(|puzzle| puzzle.stage.clone()),
+            player: player.to_string(),
+            date,
+            result: ReplayResult {
+                frames: game.history.len() as i32,
+                score: game.score,
+                total_erased: game.total_erased,
+                max_combo: game.max_combo,
+                max_erased_at_one_time: game.max_erased_at_one_time,
+                time: game.clear_time(),
+            },
+            commands: game.history.clone(),
+            checksums: game.checksums.clone(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Replay::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.to_text())
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Replay, String> {
+        let (header, body) = config::split_section(text, COMMANDS_HEADER)
+            .ok_or(format!("missing {}", COMMANDS_HEADER))?;
+        let (body, checksums) = config::split_section(body, CHECKSUMS_HEADER)
+            .ok_or(format!("missing {}", CHECKSUMS_HEADER))?;
+
+        let entries = config::parse(header)?;
+        let get = |key: &str| {
+            entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .ok_or(format!("missing `{}`", key))
+        };
+
+        let format_version: u32 = config::parse_value("format_version", get("format_version")?)?;
+        if format_version != FORMAT_VERSION {
+            return Err(format!(
+                "unsupported format version: {} (expected {})",
+                format_version, FORMAT_VERSION
+            ));
+        }
+
+        let mut rules = Rules::default();
+        let mut result = ReplayResult::default();
+        for (key, value) in &entries {
+            if let Some(key) = key.strip_prefix("rules.") {
+                rules.set(key, value)?;
+            } else if let Some(key) = key.strip_prefix("result.") {
+                match key {
+                    "frames" => result.frames = config::parse_value(key, value)?,
+                    "score" => result.score = config::parse_value(key, value)?,
+                    "total_erased" => result.total_erased = config::parse_value(key, value)?,
+                    "max_combo" => result.max_combo = config::parse_value(key, value)?,
+                    "max_erased_at_one_time" => {
+                        result.max_erased_at_one_time = config::parse_value(key, value)?
+                    }
+                    "time" => result.time = config::parse_value(key, value)?,
+                    _ => return Err(format!("unknown result: {}", key)),
+                }
+            }
+        }
+        rules.validate()?;
+
+        let mode: Mode = get("mode")?.parse()?;
+        let stage = match mode {
+            Mode::Puzzle => Some(Stage::from_entries(&entries, "stage", "", rules.clone())?),
+            _ => None,
+        };
+
+        let frames = result.frames.max(0) as usize;
+        let commands = decode_inputs(body, frames).map_err(|e| format!("commands {}", e))?;
+        let checksums = decode_checksums(checksums).map_err(|e| format!("checksums {}", e))?;
+
+        Ok(Replay {
+            game_version: get("game_version")?.to_string(),
+            seed: config::parse_value("seed", get("seed")?)?,
+            mode,
+            rules,
+            stage,
+            player: get("player").unwrap_or_default().to_string(),
+            date: config::parse_value("date", get("date").unwrap_or("0"))?,
+            result,
+            commands,
+            checksums,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "format_version = {}\ngame_version = {}\nseed = {}\nmode = {}\nplayer = {}\ndate = {}\n",
+            FORMAT_VERSION,
+            config::quote(&self.game_version),
+            self.seed,
+            config::quote(self.mode.name()),
+            config::quote(&self.player),
+            self.date,
+        );
+        text += "\n[rules]\n";
+        text += &self.rules.to_config();
+        text += &format!(
+            "\n[result]\nframes = {}\nscore = {}\ntotal_erased = {}\nmax_combo = {}\nmax_erased_at_one_time = {}\ntime = {}\n",
+            self.result.frames,
+            self.result.score,
+            self.result.total_erased,
+            self.result.max_combo,
+            self.result.max_erased_at_one_time,
+            self.result.time,
+        );
+        if let Some(stage) = &self.stage {
+            text += &stage.to_section("stage");
+        }
+        text += "\n";
+        text += COMMANDS_HEADER;
+        text += "\n";
+        text += &encode_inputs(&self.commands);
+        text += "\n";
+        text += CHECKSUMS_HEADER;
+        text += "\n";
+        text += &encode_checksums(&self.checksums);
+        text
+    }
+
+    /// このバージョンのゲームで再生できるか確認する
+    pub fn validate(&self) -> Result<(), String> {
+        if self.game_version != GAME_VERSION {
+            return Err(format!(
+                "replay was recorded with game version {}, but this is {}",
+                self.game_version, GAME_VERSION
+            ));
+        }
+        self.rules.validate()
+    }
+
+    /// 最後まで再生して、記録したチェックサムと最初に食い違ったところを返す
+    pub fn find_desync(&self) -> Option<Desync> {
+        let mut game = Game::from_replay(self);
+        for _ in 0..self.commands.len() {
+            game.update(Input::NONE);
+        }
+        Desync::find(&self.checksums, &game.checksums)
+    }
+}
+
+/// 1行に `count Input` の形でランレングス圧縮する
+pub(crate) fn encode_inputs(inputs: &[Input]) -> String {
+    let mut text = String::new();
+    let mut i = 0;
+    while i < inputs.len() {
+        let input = inputs[i];
+        let mut count = 1;
+        while i + count < inputs.len() && inputs[i + count] == input {
+            count += 1;
+        }
+        text += &format!("{} {}\n", count, input);
+        i += count;
+    }
+    text
+}
+
+/// 壊れたファイルで際限なくメモリを取らないよう、max_frames を超えたらエラーにする
+pub(crate) fn decode_inputs(text: &str, max_frames: usize) -> Result<Vec<Input>, String> {
+    let mut inputs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (count, name) = match line.split_once(' ') {
+            Some((count, name)) => (count.parse::<usize>().ok(), name.trim()),
+            None => (Some(1), line),
+        };
+        let count = count.ok_or(format!("line {}: bad count", i + 1))?;
+        let input = name
+            .parse::<Input>()
+            .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        if count > max_frames - inputs.len() {
+            return Err(format!("line {}: more than {} frames", i + 1, max_frames));
+        }
+        inputs.extend(std::iter::repeat_n(input, count));
+    }
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Command;
+
+    fn play(seed: u64, frames: usize) -> Game {
+        let mut game = Game::with_seed(seed, Rules::default());
+        let commands = [
+            Command::None,
+            Command::Left,
+            Command::Rotate,
+            Command::RotateReverse,
+            Command::SoftDrop,
+            Command::HardDrop,
+        ];
+        for i in 0..frames {
+            let mut input = Input::from(commands[(i / 7) % commands.len()]);
+            if i % 5 == 0 {
+                input.insert(Command::Right);
+            }
+            if i % 97 == 0 {
+                input.insert(Command::Pause);
+            }
+            game.update(input);
+        }
+        game
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let game = play(1, 600);
+        let replay = Replay::from_game(&game, "player \"1\"");
+        let text = replay.to_text();
+        assert!(text.contains("\n4 None\n"));
+        assert!(text.contains("\n1 Left+Right\n"));
+        assert_eq!(Replay::parse(&text), Ok(replay));
+
+        // 名前に見出しと同じ文字列があっても読める
+        let replay = Replay::from_game(&game, "[commands] [checksums]");
+        assert_eq!(Replay::parse(&replay.to_text()), Ok(replay));
+    }
+
+    #[test]
+    fn test_playback() {
+        let game = play(2, 1500);
+        let replay = Replay::from_game(&game, "");
+        let mut replayed = Game::from_replay(&Replay::parse(&replay.to_text()).unwrap());
+        for _ in 0..1500 {
+            replayed.update(Command::None);
+        }
+        assert_eq!(replayed.field, game.field);
+        assert_eq!(replayed.total_erased, game.total_erased);
+    }
+
+    #[test]
+    fn test_reject() {
+        let text = Replay::from_game(&play(3, 10), "").to_text();
+        assert!(Replay::parse(&text.replace("format_version = 4", "format_version = 3")).is_err());
+        assert!(Replay::parse(&text.replace("[checksums]\n", "[checksums]\nx\n")).is_err());
+        assert!(Replay::parse(&text[..text.find("\n[checksums]").unwrap()]).is_err());
+        assert!(Replay::parse(&text.replace("mode = \"endless\"\n", "")).is_err());
+        assert!(Replay::parse(&text.replace("\"endless\"", "\"marathon\"")).is_err());
+        assert!(Replay::parse(&text.replace("None", "Jump")).is_err());
+        // 記録されたフレーム数より長い入力は読まない
+        let long = text.replace("[commands]\n", "[commands]\n99999999999 None\n");
+        assert!(Replay::parse(&long).is_err());
+        assert_eq!(
+            decode_inputs("1 None\n2 Jump\n", 3),
+            Err("line 2: unknown command: Jump".to_string())
+        );
+        assert!(decode_inputs("2 None\n2 Left\n", 3).is_err());
+        assert!(Replay::parse(&text.replace("[commands]", "")).is_err());
+
+        let mut replay = Replay::parse(&text).unwrap();
+        assert_eq!(replay.validate(), Ok(()));
+        replay.game_version = "0.0.0".to_string();
+        assert!(replay.validate().is_err());
+    }
+}
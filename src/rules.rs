@@ -0,0 +1,237 @@
+use crate::config;
+use crate::model::{CELL_SIZE, INVISIBLE_ROW_COUNT};
+use std::path::Path;
+
+pub const MAX_COLOR_COUNT: i32 = 8;
+
+/// ゲームのルール。`Game::new` に渡す
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rules {
+    pub field_w: usize,
+    pub field_h: usize,   // 画面外の INVISIBLE_ROW_COUNT 行を含む
+    pub color_count: i32, // 1..=color_count の色を使う
+    pub block_len: usize, // 1ブロックのピース数
+    pub erase_len: usize, // この個数つながったら消す
+    pub fall_wait: i32,   // レベル0で1マス落ちるまでのフレーム数
+    pub flashing_wait: i32,
+    pub piece_fall_speed: i32, // 1フレームに落ちるピクセル数
+    pub jewels_per_level: i32, // この個数消すごとにレベルが上がる
+    pub fall_wait_step: i32,   // 1レベルごとに fall_wait を減らす量
+    pub min_fall_wait: i32,
+    pub soft_drop_wait: i32, // ソフトドロップ中に1マス落ちるまでのフレーム数
+    pub magic_interval: i32, // この個数消すごとにマジックジュエルが出る（0なら出ない）
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            field_w: 6,
+            field_h: 16,
+            color_count: 6,
+            block_len: 3,
+            erase_len: 3,
+            fall_wait: 30,
+            flashing_wait: 15,
+            piece_fall_speed: 15,
+            jewels_per_level: 35,
+            fall_wait_step: 3,
+            min_fall_wait: 3,
+            soft_drop_wait: 1,
+            magic_interval: 0,
+        }
+    }
+}
+
+impl Rules {
+    pub const PRESET_NAMES: [&'static str; 6] =
+        ["classic", "wide", "long", "four", "seven", "magic"];
+
+    pub fn preset(name: &str) -> Option<Rules> {
+        let classic = Rules::default();
+        let rules = match name {
+            "classic" => classic,
+            "wide" => Rules {
+                field_w: 8,
+                field_h: 16 + INVISIBLE_ROW_COUNT,
+                ..classic
+            },
+            "long" => Rules {
+                block_len: 4,
+                ..classic
+            },
+            "four" => Rules {
+                erase_len: 4,
+                ..classic
+            },
+            "seven" => Rules {
+                color_count: 7,
+                ..classic
+            },
+            "magic" => Rules {
+                magic_interval: 50,
+                ..classic
+            },
+            _ => return None,
+        };
+        Some(rules)
+    }
+
+    /// `key = value` 形式のテキストから読み込む。書かれていない項目は
+    /// `base`（省略時 classic）のプリセットの値になる
+    pub fn parse(text: &str) -> Result<Rules, String> {
+        let entries = config::parse(text)?;
+        let mut rules = Rules::default();
+        if let Some((_, base)) = entries.iter().find(|(key, _)| key == "base") {
+            rules = Rules::preset(base).ok_or(format!("unknown preset: {}", base))?;
+        }
+        for (key, value) in &entries {
+            if key != "base" {
+                rules.set(key, value)?;
+            }
+        }
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    /// 1項目を設定する。値の範囲は validate で確認する
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "field_w" => self.field_w = config::parse_value(key, value)?,
+            "field_h" => self.field_h = config::parse_value(key, value)?,
+            "color_count" => self.color_count = config::parse_value(key, value)?,
+            "block_len" => self.block_len = config::parse_value(key, value)?,
+            "erase_len" => self.erase_len = config::parse_value(key, value)?,
+            "fall_wait" => self.fall_wait = config::parse_value(key, value)?,
+            "flashing_wait" => self.flashing_wait = config::parse_value(key, value)?,
+            "piece_fall_speed" => self.piece_fall_speed = config::parse_value(key, value)?,
+            "jewels_per_level" => self.jewels_per_level = config::parse_value(key, value)?,
+            "fall_wait_step" => self.fall_wait_step = config::parse_value(key, value)?,
+            "min_fall_wait" => self.min_fall_wait = config::parse_value(key, value)?,
+            "soft_drop_wait" => self.soft_drop_wait = config::parse_value(key, value)?,
+            "magic_interval" => self.magic_interval = config::parse_value(key, value)?,
+            _ => return Err(format!("unknown rule: {}", key)),
+        }
+        Ok(())
+    }
+
+    /// parse で読み込める形式で書き出す
+    pub fn to_config(&self) -> String {
+        format!(
+            "field_w = {}\nfield_h = {}\ncolor_count = {}\nblock_len = {}\nerase_len = {}\nfall_wait = {}\nflashing_wait = {}\npiece_fall_speed = {}\njewels_per_level = {}\nfall_wait_step = {}\nmin_fall_wait = {}\nsoft_drop_wait = {}\nmagic_interval = {}\n",
+            self.field_w,
+            self.field_h,
+            self.color_count,
+            self.block_len,
+            self.erase_len,
+            self.fall_wait,
+            self.flashing_wait,
+            self.piece_fall_speed,
+            self.jewels_per_level,
+            self.fall_wait_step,
+            self.min_fall_wait,
+            self.soft_drop_wait,
+            self.magic_interval,
+        )
+    }
+
+    pub fn load(path: &Path) -> Result<Rules, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Rules::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// プリセット名またはファイルパスから読み込む
+    pub fn from_name_or_path(name: &str) -> Result<Rules, String> {
+        match Rules::preset(name) {
+            Some(rules) => Ok(rules),
+            None => Rules::load(Path::new(name)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.field_w < 3 {
+            return Err(format!("field_w must be at least 3: {}", self.field_w));
+        }
+        if self.block_len < 1 {
+            return Err("block_len must be at least 1".to_string());
+        }
+        if self.field_h < INVISIBLE_ROW_COUNT + self.block_len + 1 {
+            return Err(format!(
+                "field_h must be at least {}: {}",
+                INVISIBLE_ROW_COUNT + self.block_len + 1,
+                self.field_h
+            ));
+        }
+        if !(2..=MAX_COLOR_COUNT).contains(&self.color_count) {
+            return Err(format!(
+                "color_count must be in 2..={}: {}",
+                MAX_COLOR_COUNT, self.color_count
+            ));
+        }
+        if self.erase_len < 2 || self.erase_len > self.field_w.max(self.field_h) {
+            return Err(format!("erase_len is out of range: {}", self.erase_len));
+        }
+        if self.fall_wait < 1 || self.flashing_wait < 1 {
+            return Err("fall_wait and flashing_wait must be at least 1".to_string());
+        }
+        if self.jewels_per_level < 1 {
+            return Err(format!(
+                "jewels_per_level must be at least 1: {}",
+                self.jewels_per_level
+            ));
+        }
+        if self.fall_wait_step < 0 || !(1..=self.fall_wait).contains(&self.min_fall_wait) {
+            return Err(format!(
+                "fall_wait_step must be at least 0 and min_fall_wait in 1..=fall_wait: {}, {}",
+                self.fall_wait_step, self.min_fall_wait
+            ));
+        }
+        if self.soft_drop_wait < 1 {
+            return Err(format!(
+                "soft_drop_wait must be at least 1: {}",
+                self.soft_drop_wait
+            ));
+        }
+        if self.magic_interval < 0 {
+            return Err(format!(
+                "magic_interval must be at least 0: {}",
+                self.magic_interval
+            ));
+        }
+        if !(1..=CELL_SIZE).contains(&self.piece_fall_speed) {
+            return Err(format!(
+                "piece_fall_speed must be in 1..={}: {}",
+                CELL_SIZE, self.piece_fall_speed
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_are_valid() {
+        for name in Rules::PRESET_NAMES {
+            assert_eq!(Rules::preset(name).unwrap().validate(), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = Rules::parse("base = \"wide\"\nerase_len = 4\n").unwrap();
+        assert_eq!(rules.field_w, 8);
+        assert_eq!(rules.erase_len, 4);
+        assert_eq!(rules.color_count, 6);
+
+        assert!(Rules::parse("color_count = 99").is_err());
+        assert!(Rules::parse("field_width = 6").is_err());
+        assert!(Rules::parse("field_w = six").is_err());
+
+        let rules = Rules::load(Path::new("resources/rules/wide7.toml")).unwrap();
+        assert_eq!(rules.color_count, 7);
+        assert_eq!(Rules::parse(&rules.to_config()), Ok(rules));
+    }
+}
@@ -0,0 +1,309 @@
+//! Screens around the game: title, options and game over.
+//!
+//! Only the state and the menu logic live here. `main.rs` draws the current
+//! scene and turns key presses into [`MenuKey`]s.
+
+use crate::highscore::{Entry, Table};
+use crate::mode::Mode;
+use crate::model::{Command, Game};
+use crate::rules::Rules;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scene {
+    Title,
+    Options,
+    Keys, // キー割り当ての変更
+    Playing,
+    Stages, // パズルのステージ選択
+    Versus, // 2人対戦
+    Demo,   // タイトルで放っておくと AI が遊んで見せる
+    Replay, // リプレイの再生。早送りや巻き戻しができる
+    NameEntry,
+    GameOver,
+    HighScores,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    Select,
+    Back,
+}
+
+/// 縦に並んだ項目から1つを選ぶ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Menu {
+    pub cursor: usize,
+    pub len: usize,
+}
+
+impl Menu {
+    pub fn new(len: usize) -> Self {
+        Menu { cursor: 0, len }
+    }
+
+    /// 上下でカーソルを動かす。端では反対側に回り込む
+    pub fn move_cursor(&mut self, key: MenuKey) {
+        match key {
+            MenuKey::Up => self.cursor = (self.cursor + self.len - 1) % self.len,
+            MenuKey::Down => self.cursor = (self.cursor + 1) % self.len,
+            _ => {}
+        }
+    }
+
+    /// 項目の数が変わったとき。カーソルははみ出さないようにする
+    pub fn resize(&mut self, len: usize) {
+        self.len = len;
+        self.cursor = self.cursor.min(len.saturating_sub(1));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleItem {
+    Continue, // 中断したゲームがあるときだけ
+    Endless,
+    Pressure,
+    Sprint,
+    Ultra,
+    Puzzle,
+    Versus,
+    VersusAi, // 2人目を AI にする
+    HighScores,
+    Options,
+    Quit,
+}
+
+pub const TITLE_ITEMS: [TitleItem; 10] = [
+    TitleItem::Endless,
+    TitleItem::Pressure,
+    TitleItem::Sprint,
+    TitleItem::Ultra,
+    TitleItem::Puzzle,
+    TitleItem::Versus,
+    TitleItem::VersusAi,
+    TitleItem::HighScores,
+    TitleItem::Options,
+    TitleItem::Quit,
+];
+
+pub fn title_items(can_continue: bool) -> Vec<TitleItem> {
+    let mut items = Vec::new();
+    if can_continue {
+        items.push(TitleItem::Continue);
+    }
+    items.extend(TITLE_ITEMS);
+    items
+}
+
+impl TitleItem {
+    pub fn label(self) -> &'static str {
+        match self {
+            TitleItem::Continue => "CONTINUE",
+            TitleItem::Endless => "ENDLESS",
+            TitleItem::Pressure => "PRESSURE",
+            TitleItem::Sprint => "SPRINT",
+            TitleItem::Ultra => "ULTRA",
+            TitleItem::Puzzle => "PUZZLE",
+            TitleItem::Versus => "VERSUS",
+            TitleItem::VersusAi => "VS COMPUTER",
+            TitleItem::HighScores => "HIGH SCORES",
+            TitleItem::Options => "OPTIONS",
+            TitleItem::Quit => "QUIT",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionItem {
+    Volume,
+    Rules,
+    Speed,
+    Scale,
+    Fullscreen,
+    Keys,
+    Back,
+}
+
+/// 値は settings::Settings が持つ
+pub const OPTION_ITEMS: [OptionItem; 7] = [
+    OptionItem::Volume,
+    OptionItem::Rules,
+    OptionItem::Speed,
+    OptionItem::Scale,
+    OptionItem::Fullscreen,
+    OptionItem::Keys,
+    OptionItem::Back,
+];
+
+/// プリセットと一致しないルール（ファイルから読んだものなど）は "custom"
+pub fn rules_name(rules: &Rules) -> &'static str {
+    Rules::PRESET_NAMES
+        .iter()
+        .find(|name| Rules::preset(name).as_ref() == Some(rules))
+        .copied()
+        .unwrap_or("custom")
+}
+
+/// ハイスコア表のキー。モードとルールごとに分ける
+pub fn highscore_key(mode: Mode, rules_name: &str) -> String {
+    format!("{}-{}", mode.name(), rules_name)
+}
+
+pub const MAX_NAME_LEN: usize = 10;
+
+/// ハイスコアに載せる名前の入力
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameEntry {
+    pub name: String,
+}
+
+impl NameEntry {
+    pub fn new(name: &str) -> Self {
+        let mut entry = NameEntry::default();
+        entry.input(name);
+        entry
+    }
+
+    /// フォントにある ASCII の文字だけ受け付ける
+    pub fn input(&mut self, text: &str) {
+        for c in text.chars() {
+            if (c.is_ascii_graphic() || c == ' ') && self.name.len() < MAX_NAME_LEN {
+                self.name.push(c.to_ascii_uppercase());
+            }
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        self.name.pop();
+    }
+}
+
+/// ゲームオーバー画面に出す結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub score: i32,
+    pub jewels: i32,
+    pub max_combo: i32,
+    pub max_erase: i32,
+    pub seconds: i32, // 一時停止していた時間を除く
+    pub frames: i32,
+    pub mode: Mode,
+    pub high_score: bool,      // パズルとスプリントではクリアタイムの更新
+    pub cleared: Option<bool>, // パズルとスプリント・ウルトラのときだけ
+}
+
+/// 一時停止していたフレームを除いた遊んだ長さ
+pub fn play_frames(game: &Game) -> i32 {
+    let mut paused = false;
+    let mut frames = 0;
+    for input in &game.history {
+        if input.contains(Command::Pause) {
+            paused = !paused;
+        }
+        if !paused || input.contains(Command::Step) {
+            frames += 1;
+        }
+    }
+    frames
+}
+
+/// m:ss.cc 形式。fps で割って秒にする
+pub fn format_frames(frames: i32, fps: i32) -> String {
+    let fps = fps.max(1);
+    let seconds = frames / fps;
+    let hundredths = frames % fps * 100 / fps;
+    format!("{}:{:02}.{:02}", seconds / 60, seconds % 60, hundredths)
+}
+
+impl Summary {
+    /// table はこのゲームのモードとルールのハイスコア表
+    pub fn from_game(game: &Game, fps: i32, table: &Table) -> Summary {
+        // タイムアタックはカウントダウンを除いて計る
+        let frames = match &game.timer {
+            Some(timer) => timer.frames,
+            None => play_frames(game),
+        };
+        let cleared = match (&game.puzzle, &game.timer) {
+            (Some(puzzle), _) => Some(puzzle.cleared),
+            (_, Some(timer)) => Some(timer.finished),
+            _ => None,
+        };
+        Summary {
+            score: game.score,
+            jewels: game.total_erased,
+            max_combo: game.max_combo.max(0),
+            max_erase: game.max_erased_at_one_time,
+            seconds: frames / fps.max(1),
+            frames,
+            mode: game.mode,
+            high_score: table.is_best(&Entry::from_game(game)),
+            cleared,
+        }
+    }
+
+    /// m:ss 形式
+    pub fn time_played(&self) -> String {
+        format!("{}:{:02}", self.seconds / 60, self.seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::FPS;
+
+    #[test]
+    fn test_menu() {
+        let mut menu = Menu::new(3);
+        menu.move_cursor(MenuKey::Up);
+        assert_eq!(menu.cursor, 2);
+        menu.move_cursor(MenuKey::Down);
+        menu.move_cursor(MenuKey::Down);
+        assert_eq!(menu.cursor, 1);
+
+        let mut menu = Menu::new(title_items(true).len());
+        menu.move_cursor(MenuKey::Up);
+        menu.resize(title_items(false).len());
+        assert_eq!(menu.cursor, TITLE_ITEMS.len() - 1);
+    }
+
+    #[test]
+    fn test_name_entry() {
+        let mut entry = NameEntry::new("aoyama\u{3042}");
+        assert_eq!(entry.name, "AOYAMA");
+        entry.input("-val-long");
+        assert_eq!(entry.name, "AOYAMA-VAL");
+        entry.backspace();
+        assert_eq!(entry.name, "AOYAMA-VA");
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut game = Game::with_seed(0, Rules::default());
+        for i in 0..(FPS * 70) {
+            game.update(if i == FPS * 10 {
+                Command::Pause
+            } else {
+                Command::None
+            });
+        }
+        game.score = 100;
+        let table = |score: i32| Table {
+            entries: vec![Entry {
+                score,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let summary = Summary::from_game(&game, FPS, &table(100));
+        assert_eq!(summary.time_played(), "0:10");
+        assert_eq!(summary.cleared, None);
+        assert_eq!(format_frames(summary.frames, FPS), "0:10.00");
+        assert_eq!(format_frames(FPS * 65 + FPS / 2, FPS), "1:05.50");
+        assert!(!summary.high_score);
+        assert!(Summary::from_game(&game, FPS, &table(99)).high_score);
+    }
+}
@@ -0,0 +1,51 @@
+//! Scoring and level progression.
+
+use crate::rules::Rules;
+
+pub const POINTS_PER_JEWEL: i32 = 10;
+pub const MULTI_LINE_BONUS: i32 = 50; // 2列目以降の1列ごと
+pub const DIAGONAL_BONUS: i32 = 30; // 斜め1列ごと
+pub const SOFT_DROP_POINTS: i32 = 1; // ソフトドロップで1マス落とすごと
+pub const HARD_DROP_POINTS: i32 = 2; // ハードドロップで1マス落とすごと
+
+/// 1回の消去の得点。chain は 0 始まりの連鎖数
+pub fn erase_points(erased: i32, lines: i32, diagonal_lines: i32, chain: i32, level: i32) -> i32 {
+    let mut points = erased * POINTS_PER_JEWEL;
+    if lines > 1 {
+        points += (lines - 1) * MULTI_LINE_BONUS;
+    }
+    points += diagonal_lines * DIAGONAL_BONUS;
+    points * (chain + 1) * (level + 1)
+}
+
+pub fn level(total_erased: i32, rules: &Rules) -> i32 {
+    total_erased / rules.jewels_per_level
+}
+
+/// レベルに応じた落下のウェイト
+pub fn fall_wait(level: i32, rules: &Rules) -> i32 {
+    (rules.fall_wait - level * rules.fall_wait_step).max(rules.min_fall_wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_erase_points() {
+        assert_eq!(erase_points(3, 1, 0, 0, 0), 30);
+        assert_eq!(erase_points(5, 2, 0, 0, 0), 100);
+        assert_eq!(erase_points(3, 1, 1, 0, 0), 60);
+        assert_eq!(erase_points(3, 1, 0, 2, 1), 180);
+    }
+
+    #[test]
+    fn test_level() {
+        let rules = Rules::default();
+        assert_eq!(level(rules.jewels_per_level - 1, &rules), 0);
+        assert_eq!(level(rules.jewels_per_level, &rules), 1);
+        assert_eq!(fall_wait(0, &rules), rules.fall_wait);
+        assert!(fall_wait(1, &rules) < rules.fall_wait);
+        assert_eq!(fall_wait(1000, &rules), rules.min_fall_wait);
+    }
+}
@@ -0,0 +1,594 @@
+//! User settings kept in `settings.toml` in the config directory.
+//!
+//! Every key is optional and falls back to the same default the game uses
+//! without a settings file. Command-line options override the file for one
+//! run.
+//!
+//! ```text
+//! volume = 10
+//! rules = "classic"   # preset name or rule file, or a [rules] section
+//! fps = 30
+//! scale = 1
+//! fullscreen = false
+//! das = 7
+//! arr = 2
+//!
+//! [keys]              # SDL key names, several separated by commas
+//! left = "Left"
+//! rotate = "Space, X"
+//!
+//! [buttons]           # SDL game controller button names
+//! rotate = "a"
+//!
+//! [keys2]             # player 2 in versus; pause and step come from [keys]
+//! left = "A"
+//! ```
+
+use crate::cli::Options;
+use crate::config;
+use crate::input::{DEFAULT_ARR, DEFAULT_DAS};
+use crate::model::{Command, FPS};
+use crate::rules::Rules;
+use crate::scene::{rules_name, OptionItem};
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = "settings.toml";
+pub const MAX_VOLUME: i32 = 10;
+pub const SPEEDS: [i32; 5] = [15, 20, FPS, 45, 60]; // 選べるフレームレート
+
+/// 操作ごとに割り当てた物理入力（SDL のキー名やゲームパッドのボタン名）。
+/// 1つの操作に複数を割り当てられる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    inputs: [Vec<String>; 8], // ACTIONS の順
+}
+
+impl Bindings {
+    pub const ACTIONS: [&'static str; 8] = [
+        "left",
+        "right",
+        "down",
+        "rotate",
+        "rotate_reverse",
+        "hard_drop",
+        "pause",
+        "step",
+    ];
+
+    /// ACTIONS と同じ順で、それぞれの操作が出すコマンド
+    pub const COMMANDS: [Command; 8] = [
+        Command::Left,
+        Command::Right,
+        Command::SoftDrop,
+        Command::Rotate,
+        Command::RotateReverse,
+        Command::HardDrop,
+        Command::Pause,
+        Command::Step,
+    ];
+
+    fn new(names: [&str; 8]) -> Bindings {
+        Bindings {
+            inputs: names.map(|name| vec![name.to_string()]),
+        }
+    }
+
+    pub fn default_keys() -> Bindings {
+        Bindings::new(["Left", "Right", "Down", "Space", "Z", "Up", "P", "."])
+    }
+
+    /// 対戦の2人目。一時停止とコマ送りは1人目のキーで操作する
+    pub fn default_keys2() -> Bindings {
+        let mut bindings = Bindings::new(["A", "D", "S", "E", "Q", "W", "", ""]);
+        bindings.inputs[6].clear();
+        bindings.inputs[7].clear();
+        bindings
+    }
+
+    /// SDL の GameController のボタン名
+    pub fn default_buttons() -> Bindings {
+        Bindings::new([
+            "dpleft", "dpright", "dpdown", "a", "b", "dpup", "start", "back",
+        ])
+    }
+
+    fn index(action: &str) -> Result<usize, String> {
+        Bindings::ACTIONS
+            .iter()
+            .position(|&a| a == action)
+            .ok_or(format!("unknown action: {}", action))
+    }
+
+    pub fn get(&self, action: &str) -> &[String] {
+        Bindings::index(action)
+            .map(|i| self.inputs[i].as_slice())
+            .unwrap_or_default()
+    }
+
+    /// "Space, X" のようにカンマ区切りで複数指定できる。名前の中のカンマは \, と書く
+    pub fn set(&mut self, action: &str, value: &str) -> Result<(), String> {
+        let i = Bindings::index(action)?;
+        let mut names = vec![String::new()];
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => names.last_mut().unwrap().extend(chars.next()),
+                ',' => names.push(String::new()),
+                _ => names.last_mut().unwrap().push(c),
+            }
+        }
+        self.inputs[i] = names
+            .iter()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        Ok(())
+    }
+
+    pub fn to_value(&self, action: &str) -> String {
+        let names: Vec<String> = self
+            .get(action)
+            .iter()
+            .map(|name| name.replace('\\', "\\\\").replace(',', "\\,"))
+            .collect();
+        names.join(", ")
+    }
+
+    /// action を name だけにする。name をほかの操作で使っていたら、その操作から外し、
+    /// 空になったときは action の元の割り当てを渡す
+    pub fn assign(&mut self, action: &str, name: &str) -> Result<(), String> {
+        let i = Bindings::index(action)?;
+        let old = std::mem::replace(&mut self.inputs[i], vec![name.to_string()]);
+        for (j, inputs) in self.inputs.iter_mut().enumerate() {
+            if j == i || !inputs.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            inputs.retain(|n| !n.eq_ignore_ascii_case(name));
+            if inputs.is_empty() {
+                *inputs = old
+                    .iter()
+                    .filter(|n| !n.eq_ignore_ascii_case(name))
+                    .cloned()
+                    .collect();
+            }
+        }
+        Ok(())
+    }
+
+    /// name をどの操作からも外す
+    pub fn remove(&mut self, name: &str) {
+        for inputs in &mut self.inputs {
+            inputs.retain(|n| !n.eq_ignore_ascii_case(name));
+        }
+    }
+
+    /// 物理入力の名前と、それで出すコマンドの組
+    pub fn commands(&self) -> impl Iterator<Item = (&str, Command)> {
+        self.inputs
+            .iter()
+            .zip(Bindings::COMMANDS)
+            .flat_map(|(names, command)| names.iter().map(move |name| (name.as_str(), command)))
+    }
+
+    /// 同じ入力を2つの操作に割り当てたものをはじく。section は設定ファイルの節の名前
+    pub fn validate(&self, section: &str, allow_empty: bool) -> Result<(), String> {
+        for (i, action) in Bindings::ACTIONS.iter().enumerate() {
+            if self.inputs[i].is_empty() && !allow_empty {
+                return Err(format!("nothing bound to `{}.{}`", section, action));
+            }
+            for name in &self.inputs[i] {
+                if let Some(other) =
+                    (0..i).find(|&j| self.inputs[j].iter().any(|n| n.eq_ignore_ascii_case(name)))
+                {
+                    return Err(format!(
+                        "{} is bound to both `{}.{}` and `{}.{}`",
+                        name,
+                        section,
+                        Bindings::ACTIONS[other],
+                        section,
+                        action
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub volume: i32, // 0 から MAX_VOLUME
+    pub rules: Rules,
+    pub fps: i32,
+    pub scale: f32,
+    pub fullscreen: bool,
+    pub das: i32,
+    pub arr: i32,
+    pub keys: Bindings,
+    pub keys2: Bindings,   // 対戦の2人目
+    pub buttons: Bindings, // ゲームパッド。対戦では2台目が2人目
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            volume: MAX_VOLUME,
+            rules: Rules::default(),
+            fps: FPS,
+            scale: 1.0,
+            fullscreen: false,
+            das: DEFAULT_DAS,
+            arr: DEFAULT_ARR,
+            keys: Bindings::default_keys(),
+            keys2: Bindings::default_keys2(),
+            buttons: Bindings::default_buttons(),
+        }
+    }
+}
+
+/// 設定ファイルの場所。ホームが分からなければ None
+pub fn default_path() -> Option<PathBuf> {
+    crate::paths::config_dir().map(|dir| dir.join(FILE_NAME))
+}
+
+impl Settings {
+    pub fn parse(text: &str) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        let mut custom_rules = None;
+        for (key, value) in config::parse(text)? {
+            let value = value.as_str();
+            if let Some(action) = key.strip_prefix("keys.") {
+                settings.keys.set(action, value)?;
+                continue;
+            }
+            if let Some(action) = key.strip_prefix("keys2.") {
+                settings.keys2.set(action, value)?;
+                continue;
+            }
+            if let Some(action) = key.strip_prefix("buttons.") {
+                settings.buttons.set(action, value)?;
+                continue;
+            }
+            if let Some(rule) = key.strip_prefix("rules.") {
+                custom_rules
+                    .get_or_insert_with(Rules::default)
+                    .set(rule, value)?;
+                continue;
+            }
+            match key.as_str() {
+                "volume" => settings.volume = config::parse_value(&key, value)?,
+                "rules" => settings.rules = Rules::from_name_or_path(value)?,
+                "fps" => settings.fps = config::parse_value(&key, value)?,
+                "scale" => settings.scale = config::parse_value(&key, value)?,
+                "fullscreen" => settings.fullscreen = config::parse_value(&key, value)?,
+                "das" => settings.das = config::parse_value(&key, value)?,
+                "arr" => settings.arr = config::parse_value(&key, value)?,
+                _ => return Err(format!("unknown setting: {}", key)),
+            }
+        }
+        if let Some(rules) = custom_rules {
+            settings.rules = rules;
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// プリセットのルールは名前だけ、それ以外は [rules] に全部書く
+    pub fn to_text(&self) -> String {
+        let mut text = format!("volume = {}\n", self.volume);
+        let custom = rules_name(&self.rules) == "custom";
+        if !custom {
+            text += &format!("rules = {}\n", config::quote(rules_name(&self.rules)));
+        }
+        text += &format!(
+            "fps = {}\nscale = {}\nfullscreen = {}\ndas = {}\narr = {}\n",
+            self.fps, self.scale, self.fullscreen, self.das, self.arr
+        );
+        if custom {
+            text += "\n[rules]\n";
+            text += &self.rules.to_config();
+        }
+        let sections = [
+            ("keys", &self.keys),
+            ("buttons", &self.buttons),
+            ("keys2", &self.keys2),
+        ];
+        for (section, bindings) in sections {
+            text += &format!("\n[{}]\n", section);
+            for action in Bindings::ACTIONS {
+                text += &format!(
+                    "{} = {}\n",
+                    action,
+                    config::quote(&bindings.to_value(action))
+                );
+            }
+        }
+        text
+    }
+
+    /// ファイルが無ければ既定値
+    pub fn load(path: &Path) -> Result<Settings, String> {
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Settings::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        }
+        std::fs::write(path, self.to_text())
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0..=MAX_VOLUME).contains(&self.volume) {
+            return Err(format!(
+                "volume must be in 0..={}: {}",
+                MAX_VOLUME, self.volume
+            ));
+        }
+        if !(1..=240).contains(&self.fps) {
+            return Err(format!("fps must be in 1..=240: {}", self.fps));
+        }
+        if !(1.0..=4.0).contains(&self.scale) {
+            return Err(format!("scale must be in 1..=4: {}", self.scale));
+        }
+        if self.das < 1 || self.arr < 1 {
+            return Err("das and arr must be at least 1".to_string());
+        }
+        self.rules.validate()?;
+        self.keys.validate("keys", false)?;
+        self.keys2.validate("keys2", true)?;
+        // 対戦では同じキーボードを分けて使う
+        for (name, _) in self.keys2.commands() {
+            if let Some((other, _)) = self
+                .keys
+                .commands()
+                .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            {
+                return Err(format!("{} is bound in both [keys] and [keys2]", other));
+            }
+        }
+        self.buttons.validate("buttons", true)
+    }
+
+    /// キー割り当て画面で [keys] の action を name にする。
+    /// 2人目が使っていたキーなら [keys2] からは外す（空の操作は許される）
+    pub fn assign_key(&mut self, action: &str, name: &str) -> Result<(), String> {
+        self.keys.assign(action, name)?;
+        self.keys2.remove(name);
+        Ok(())
+    }
+
+    /// コマンドライン引数を解析するときの既定値
+    pub fn to_options(&self) -> Options {
+        Options {
+            rules: self.rules.clone(),
+            scale: self.scale,
+            fps: self.fps,
+            das: self.das,
+            arr: self.arr,
+            fullscreen: self.fullscreen,
+            ..Options::default()
+        }
+    }
+
+    /// コマンドラインで上書きされた値を取り込む
+    pub fn apply_options(&mut self, options: &Options) {
+        self.rules = options.rules.clone();
+        self.scale = options.scale;
+        self.fps = options.fps;
+        self.das = options.das;
+        self.arr = options.arr;
+        self.fullscreen = options.fullscreen;
+    }
+
+    /// オプション画面で before から after に変えた項目だけを取り込む。
+    /// コマンドラインで上書きしただけの値はファイルに残さない
+    pub fn merge_changes(&mut self, before: &Settings, after: &Settings) {
+        if after.volume != before.volume {
+            self.volume = after.volume;
+        }
+        if after.rules != before.rules {
+            self.rules = after.rules.clone();
+        }
+        if after.fps != before.fps {
+            self.fps = after.fps;
+        }
+        if after.scale != before.scale {
+            self.scale = after.scale;
+        }
+        if after.fullscreen != before.fullscreen {
+            self.fullscreen = after.fullscreen;
+        }
+        if after.das != before.das {
+            self.das = after.das;
+        }
+        if after.arr != before.arr {
+            self.arr = after.arr;
+        }
+        if after.keys != before.keys {
+            self.keys = after.keys.clone();
+        }
+        if after.keys2 != before.keys2 {
+            self.keys2 = after.keys2.clone();
+        }
+        if after.buttons != before.buttons {
+            self.buttons = after.buttons.clone();
+        }
+    }
+
+    pub fn rules_name(&self) -> &'static str {
+        rules_name(&self.rules)
+    }
+
+    pub fn label(&self, item: OptionItem) -> String {
+        match item {
+            OptionItem::Volume => format!("VOLUME {:>6}", self.volume),
+            OptionItem::Rules => format!("RULES {:>7}", self.rules_name().to_uppercase()),
+            OptionItem::Speed => format!("SPEED {:>7}", self.fps),
+            OptionItem::Scale => format!("SCALE {:>7}", self.scale),
+            OptionItem::Fullscreen => {
+                format!(
+                    "FULLSCREEN {:>3}",
+                    if self.fullscreen { "ON" } else { "OFF" }
+                )
+            }
+            OptionItem::Keys => "KEYS".to_string(),
+            OptionItem::Back => "BACK".to_string(),
+        }
+    }
+
+    /// 左右キーで値を変える
+    pub fn change(&mut self, item: OptionItem, delta: i32) {
+        match item {
+            OptionItem::Volume => self.volume = (self.volume + delta).clamp(0, MAX_VOLUME),
+            OptionItem::Rules => {
+                let len = Rules::PRESET_NAMES.len() as i32;
+                let next = match Rules::PRESET_NAMES
+                    .iter()
+                    .position(|&n| n == self.rules_name())
+                {
+                    Some(i) => (i as i32 + delta).rem_euclid(len),
+                    None => 0,
+                };
+                self.rules = Rules::preset(Rules::PRESET_NAMES[next as usize]).unwrap();
+            }
+            OptionItem::Speed => {
+                let len = SPEEDS.len() as i32;
+                let next = match SPEEDS.iter().position(|&fps| fps == self.fps) {
+                    Some(i) => (i as i32 + delta).clamp(0, len - 1),
+                    None => SPEEDS.iter().position(|&fps| fps == FPS).unwrap() as i32,
+                };
+                self.fps = SPEEDS[next as usize];
+            }
+            OptionItem::Scale => {
+                self.scale = (self.scale.round() + delta as f32).clamp(1.0, 4.0);
+            }
+            OptionItem::Fullscreen => self.fullscreen = !self.fullscreen,
+            OptionItem::Keys | OptionItem::Back => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Settings::parse(""), Ok(Settings::default()));
+
+        let settings =
+            Settings::parse("volume = 3\nrules = \"wide\"\nscale = 2\n[keys]\nrotate = \"X\"\n")
+                .unwrap();
+        assert_eq!(settings.volume, 3);
+        assert_eq!(settings.rules, Rules::preset("wide").unwrap());
+        assert_eq!(settings.scale, 2.0);
+        assert_eq!(settings.keys.get("rotate"), ["X"]);
+        assert_eq!(Settings::parse(&settings.to_text()), Ok(settings.clone()));
+
+        let mut custom = settings;
+        custom.rules.color_count = 5;
+        assert_eq!(Settings::parse(&custom.to_text()), Ok(custom));
+
+        assert!(Settings::parse("volume = 11").is_err());
+        assert!(Settings::parse("volume = loud").is_err());
+        assert!(Settings::parse("rules = \"nothing\"").is_err());
+        assert!(Settings::parse("speed = 30").is_err());
+        assert!(Settings::parse("[keys]\njump = \"J\"").is_err());
+        assert!(Settings::parse("[keys]\nrotate = \"left\"").is_err());
+        assert!(Settings::parse("[keys]\nrotate = \"\"").is_err());
+        assert!(Settings::parse("[buttons]\nstep = \"\"").is_ok());
+        assert!(Settings::parse("[keys2]\nleft = \"Left\"").is_err());
+        assert!(Settings::parse("[keys2]\nleft = \"J\"\npause = \"\"").is_ok());
+    }
+
+    #[test]
+    fn test_merge_changes() {
+        let saved = Settings::parse("volume = 8\nfps = 20").unwrap();
+        // --fps 60 --rules wide で起動して、オプション画面で音量だけ変えた
+        let mut running = saved.clone();
+        running.apply_options(&Options {
+            fps: 60,
+            rules: Rules::preset("wide").unwrap(),
+            ..saved.to_options()
+        });
+        let before = running.clone();
+        running.change(OptionItem::Volume, -1);
+
+        let mut merged = saved.clone();
+        merged.merge_changes(&before, &running);
+        assert_eq!(merged.volume, 7);
+        assert_eq!(merged.fps, 20);
+        assert_eq!(merged.rules, Rules::default());
+
+        // オプション画面で変えたものは、上書きしていた値でも保存する
+        running.change(OptionItem::Speed, -1);
+        merged.merge_changes(&before, &running);
+        assert_eq!(merged.fps, 45);
+    }
+
+    #[test]
+    fn test_bindings() {
+        let mut keys = Bindings::default_keys();
+        keys.set("rotate", "Space, X").unwrap();
+        assert_eq!(keys.get("rotate"), ["Space", "X"]);
+        assert_eq!(keys.to_value("rotate"), "Space, X");
+        // カンマのキーも、ほかのキーと並べて書き戻せる
+        keys.set("step", "., \\,, Keypad \\,, \\\\").unwrap();
+        assert_eq!(keys.get("step"), [".", ",", "Keypad ,", "\\"]);
+        let mut parsed = Bindings::default_keys();
+        parsed.set("step", &keys.to_value("step")).unwrap();
+        assert_eq!(parsed.get("step"), keys.get("step"));
+        let settings = Settings {
+            keys: keys.clone(),
+            ..Settings::default()
+        };
+        assert_eq!(Settings::parse(&settings.to_text()), Ok(settings));
+        assert!(keys
+            .commands()
+            .any(|(name, command)| name == "X" && command == Command::Rotate));
+
+        // 使用中のキーは取り上げ、空になった操作には元の割り当てを渡す
+        keys.assign("rotate", "Z").unwrap();
+        assert_eq!(keys.get("rotate"), ["Z"]);
+        assert_eq!(keys.get("rotate_reverse"), ["Space", "X"]);
+        keys.assign("left", "X").unwrap();
+        assert_eq!(keys.get("rotate_reverse"), ["Space"]);
+        assert_eq!(keys.validate("keys", false), Ok(()));
+        assert!(keys.assign("jump", "J").is_err());
+
+        // 2人目のキーを1人目に割り当てると、2人目からは外れる
+        let mut settings = Settings::default();
+        assert_eq!(settings.keys2.get("left"), ["A"]);
+        settings.assign_key("rotate", "A").unwrap();
+        assert_eq!(settings.keys.get("rotate"), ["A"]);
+        assert!(settings.keys2.get("left").is_empty());
+        assert_eq!(settings.validate(), Ok(()));
+        assert_eq!(Settings::parse(&settings.to_text()), Ok(settings));
+    }
+
+    #[test]
+    fn test_change() {
+        let mut settings = Settings::default();
+        settings.change(OptionItem::Volume, 1);
+        assert_eq!(settings.volume, MAX_VOLUME);
+        settings.change(OptionItem::Rules, -1);
+        assert_eq!(settings.rules_name(), "magic");
+        settings.change(OptionItem::Speed, 1);
+        assert_eq!(settings.fps, 45);
+        settings.change(OptionItem::Scale, -1);
+        assert_eq!(settings.scale, 1.0);
+
+        settings.rules.color_count = 5;
+        assert_eq!(settings.rules_name(), "custom");
+        settings.change(OptionItem::Rules, 1);
+        assert_eq!(settings.rules_name(), "classic");
+    }
+}
@@ -0,0 +1,104 @@
+//! Running games to the end without a window, as fast as possible.
+
+use crate::bot::Bot;
+use crate::model::{Game, Input};
+use crate::replay::Replay;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub seed: u64,
+    pub frames: i32,
+    pub score: i32,
+    pub level: i32,
+    pub total_erased: i32,
+    pub max_combo: i32,
+    pub max_erased_at_one_time: i32,
+    pub is_over: bool,
+}
+
+impl Stats {
+    pub fn from_game(game: &Game) -> Stats {
+        Stats {
+            seed: game.seed,
+            frames: game.frame + 1,
+            score: game.score,
+            level: game.level,
+            total_erased: game.total_erased,
+            max_combo: game.max_combo,
+            max_erased_at_one_time: game.max_erased_at_one_time,
+            is_over: game.is_over,
+        }
+    }
+
+    pub const CSV_HEADER: &'static str =
+        "seed,frames,score,level,total_erased,max_combo,max_erased_at_one_time,is_over";
+
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{}",
+            self.seed,
+            self.frames,
+            self.score,
+            self.level,
+            self.total_erased,
+            self.max_combo,
+            self.max_erased_at_one_time,
+            self.is_over
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"seed\":{},\"frames\":{},\"score\":{},\"level\":{},\"total_erased\":{},\"max_combo\":{},\"max_erased_at_one_time\":{},\"is_over\":{}}}",
+            self.seed,
+            self.frames,
+            self.score,
+            self.level,
+            self.total_erased,
+            self.max_combo,
+            self.max_erased_at_one_time,
+            self.is_over
+        )
+    }
+}
+
+/// リプレイの入力が尽きるか、ゲームオーバーになるまで進める
+pub fn run_replay(replay: &Replay) -> Game {
+    let mut game = Game::from_replay(replay);
+    while !game.is_over && ((game.frame + 1) as usize) < replay.commands.len() {
+        game.update(Input::NONE);
+        game.requested_sounds.clear();
+    }
+    game
+}
+
+/// ゲームオーバーになるか max_frames に達するまで bot に操作させる
+pub fn run_bot(mut game: Game, bot: &mut dyn Bot, max_frames: i32) -> Game {
+    while !game.is_over && game.frame + 1 < max_frames {
+        let input = bot.input(&game);
+        game.update(input);
+        game.requested_sounds.clear();
+    }
+    game
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::RandomBot;
+    use crate::rules::Rules;
+
+    #[test]
+    fn test_run_replay() {
+        let game = run_bot(
+            Game::with_seed(7, Rules::default()),
+            &mut RandomBot::new(7),
+            100_000,
+        );
+        assert!(game.is_over);
+
+        let replay = Replay::from_game(&game, "");
+        let replayed = run_replay(&replay);
+        assert_eq!(Stats::from_game(&replayed), Stats::from_game(&game));
+    }
+}
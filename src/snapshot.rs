@@ -0,0 +1,440 @@
+//! Snapshots of the whole game state.
+//!
+//! A snapshot is a text file holding every field of a [`Game`] that affects
+//! what happens next, so a game can be suspended and resumed, attached to a
+//! bug report, or used as the starting position of a test. The random number
+//! generator is stored as the seed and the number of colors drawn from it,
+//! and restored by drawing again. A puzzle also stores its progress in
+//! `[puzzle]` and a copy of the stage (see [`crate::puzzle`]), and sprint and
+//! ultra store their clock in `[timer]`.
+//!
+//! ```text
+//! format_version = 1
+//! game_version = "0.1.0"
+//! seed = 1706226338
+//! mode = "endless"
+//! rng_draws = 42
+//!
+//! [rules]
+//! ...
+//!
+//! [state]
+//! frame = 812
+//! state = "Controllable"
+//! current = "3 1 4"
+//! ...
+//!
+//! [field]
+//! 15 = "4 5 1 3 2 2"
+//! ...
+//!
+//! [history]
+//! 29 None
+//! 1 Left
+//!
+//! [checksums]
+//! 45 9f3a1c0e7b2d4410 000000/000000/.../001000/005000
+//! ```
+
+use crate::config;
+use crate::desync::{decode_checksums, encode_checksums};
+use crate::mode::{Mode, Timer};
+use crate::model::{Game, State};
+use crate::paths;
+use crate::puzzle::{Puzzle, Stage};
+use crate::replay::{decode_inputs, encode_inputs, GAME_VERSION, MAX_FRAMES};
+use crate::rules::Rules;
+use std::path::{Path, PathBuf};
+
+pub const FORMAT_VERSION: u32 = 1;
+
+/// タイトルに戻るときに中断したゲームを置くファイル
+pub const SUSPEND_FILE: &str = "suspend.state";
+
+pub fn suspend_path() -> Option<PathBuf> {
+    paths::data_dir().map(|dir| dir.join(SUSPEND_FILE))
+}
+
+/// 不具合の報告用に保存する場所。シードとフレームで名前を付ける
+pub fn report_path(game: &Game) -> Option<PathBuf> {
+    paths::data_dir().map(|dir| {
+        dir.join("snapshots")
+            .join(format!("{}-{}.state", game.seed, game.frame))
+    })
+}
+
+const HISTORY_HEADER: &str = "[history]";
+const CHECKSUMS_HEADER: &str = "[checksums]";
+const COMMANDS_HEADER: &str = "[commands]"; // リプレイ再生中のときだけ
+
+fn join<T: ToString>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(" ")
+}
+
+fn split<T: std::str::FromStr>(key: &str, value: &str) -> Result<Vec<T>, String> {
+    value
+        .split_whitespace()
+        .map(|v| config::parse_value(key, v))
+        .collect()
+}
+
+fn parse_state(value: &str) -> Result<State, String> {
+    match value {
+        "Controllable" => Ok(State::Controllable),
+        "Flashing" => Ok(State::Flashing),
+        "PieceFalling" => Ok(State::PieceFalling),
+        _ => Err(format!("unknown state: {}", value)),
+    }
+}
+
+impl Game {
+    pub fn to_snapshot(&self) -> String {
+        let mut text = format!(
+            "format_version = {}\ngame_version = {}\nseed = {}\nmode = {}\nrng_draws = {}\n",
+            FORMAT_VERSION,
+            config::quote(GAME_VERSION),
+            self.seed,
+            config::quote(self.mode.name()),
+            self.rng_draws,
+        );
+        text += "\n[rules]\n";
+        text += &self.rules.to_config();
+
+        text += "\n[state]\n";
+        let values = [
+            ("frame", self.frame.to_string()),
+            ("is_over", self.is_over.to_string()),
+            ("replay_loaded", self.replay_loaded.to_string()),
+            ("settles", self.settles.to_string()),
+            ("state", config::quote(&format!("{:?}", self.state))),
+            ("paused", self.paused.to_string()),
+            ("current_x", self.current_x.to_string()),
+            ("current_y", self.current_y.to_string()),
+            ("current", config::quote(&join(&self.current))),
+            ("next", config::quote(&join(&self.next))),
+            (
+                "next_to_display",
+                config::quote(&join(&self.next_to_display)),
+            ),
+            ("erased_one_time", self.erased_one_time.to_string()),
+            (
+                "max_erased_at_one_time",
+                self.max_erased_at_one_time.to_string(),
+            ),
+            ("combo", self.combo.to_string()),
+            ("total_erased", self.total_erased.to_string()),
+            ("max_combo", self.max_combo.to_string()),
+            ("score", self.score.to_string()),
+            ("level", self.level.to_string()),
+            ("erase_lines", self.erase_lines.to_string()),
+            (
+                "erase_diagonal_lines",
+                self.erase_diagonal_lines.to_string(),
+            ),
+            ("soft_drop", self.soft_drop.to_string()),
+            ("landed", self.landed.to_string()),
+            ("magic_pending", self.magic_pending.to_string()),
+            ("fall_wait", self.fall_wait.to_string()),
+            ("spawn_wait", self.spawn_wait.to_string()),
+            ("flashing_wait", self.flashing_wait.to_string()),
+            ("piece_fall_wait", self.piece_fall_wait.to_string()),
+            ("piece_fall_offset", self.piece_fall_offset.to_string()),
+            ("pressure_wait", self.pressure_wait.to_string()),
+        ];
+        for (key, value) in values {
+            text += &format!("{} = {}\n", key, value);
+        }
+
+        // 行番号をキーにする
+        let grids: [(&str, Vec<String>); 3] = [
+            ("field", self.field.iter().map(|row| join(row)).collect()),
+            (
+                "check_erase_result",
+                self.check_erase_result
+                    .iter()
+                    .map(|row| join(&row.iter().map(|&b| b as i32).collect::<Vec<_>>()))
+                    .collect(),
+            ),
+            (
+                "piece_falling",
+                self.piece_falling
+                    .iter()
+                    .map(|row| join(&row.iter().map(|&b| b as i32).collect::<Vec<_>>()))
+                    .collect(),
+            ),
+        ];
+        for (name, rows) in grids {
+            text += &format!("\n[{}]\n", name);
+            for (y, row) in rows.iter().enumerate() {
+                text += &format!("{} = {}\n", y, config::quote(row));
+            }
+        }
+
+        if let Some(puzzle) = &self.puzzle {
+            let target = puzzle
+                .target
+                .map(|(x, y)| format!("{} {}", x, y))
+                .unwrap_or_default();
+            text += &format!(
+                "\n[puzzle]\nnext_piece = {}\ntarget = {}\ncleared = {}\n",
+                puzzle.next_piece,
+                config::quote(&target),
+                puzzle.cleared
+            );
+            text += &puzzle.stage.to_section("stage");
+        }
+        if let Some(timer) = &self.timer {
+            text += &format!(
+                "\n[timer]\ncountdown = {}\nframes = {}\nfinished = {}\n",
+                timer.countdown, timer.frames, timer.finished
+            );
+        }
+
+        text += "\n";
+        text += HISTORY_HEADER;
+        text += "\n";
+        text += &encode_inputs(&self.history);
+        text += CHECKSUMS_HEADER;
+        text += "\n";
+        text += &encode_checksums(&self.checksums);
+        if self.replay_loaded {
+            text += COMMANDS_HEADER;
+            text += "\n";
+            text += &encode_inputs(&self.commands);
+        }
+        text
+    }
+
+    pub fn from_snapshot(text: &str) -> Result<Game, String> {
+        let (header, inputs) = config::split_section(text, HISTORY_HEADER)
+            .ok_or(format!("missing {}", HISTORY_HEADER))?;
+        let (inputs, commands) =
+            config::split_section(inputs, COMMANDS_HEADER).unwrap_or((inputs, ""));
+        let (history, checksums) =
+            config::split_section(inputs, CHECKSUMS_HEADER).unwrap_or((inputs, ""));
+
+        let entries = config::parse(header)?;
+        let get = |key: &str| {
+            entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .ok_or(format!("missing `{}`", key))
+        };
+
+        let format_version: u32 = config::parse_value("format_version", get("format_version")?)?;
+        if format_version != FORMAT_VERSION {
+            return Err(format!(
+                "unsupported format version: {} (expected {})",
+                format_version, FORMAT_VERSION
+            ));
+        }
+        let game_version = get("game_version")?;
+        if game_version != GAME_VERSION {
+            return Err(format!(
+                "snapshot was saved by game version {}, but this is {}",
+                game_version, GAME_VERSION
+            ));
+        }
+
+        let mut rules = Rules::default();
+        for (key, value) in &entries {
+            if let Some(key) = key.strip_prefix("rules.") {
+                rules.set(key, value)?;
+            }
+        }
+        rules.validate()?;
+
+        let mut game = Game::with_seed(config::parse_value("seed", get("seed")?)?, rules);
+        game.restore_rng(config::parse_value("rng_draws", get("rng_draws")?)?);
+        game.mode = get("mode").unwrap_or("endless").parse()?;
+        game.requested_sounds.clear();
+
+        for (key, value) in &entries {
+            let value = value.as_str();
+            if let Some(key) = key.strip_prefix("state.") {
+                match key {
+                    "frame" => game.frame = config::parse_value(key, value)?,
+                    "is_over" => game.is_over = config::parse_value(key, value)?,
+                    "replay_loaded" => game.replay_loaded = config::parse_value(key, value)?,
+                    "settles" => game.settles = config::parse_value(key, value)?,
+                    "state" => game.state = parse_state(value)?,
+                    "paused" => game.paused = config::parse_value(key, value)?,
+                    "current_x" => game.current_x = config::parse_value(key, value)?,
+                    "current_y" => game.current_y = config::parse_value(key, value)?,
+                    "current" => game.current = split(key, value)?,
+                    "next" => game.next = split(key, value)?,
+                    "next_to_display" => game.next_to_display = split(key, value)?,
+                    "erased_one_time" => game.erased_one_time = config::parse_value(key, value)?,
+                    "max_erased_at_one_time" => {
+                        game.max_erased_at_one_time = config::parse_value(key, value)?
+                    }
+                    "combo" => game.combo = config::parse_value(key, value)?,
+                    "total_erased" => game.total_erased = config::parse_value(key, value)?,
+                    "max_combo" => game.max_combo = config::parse_value(key, value)?,
+                    "score" => game.score = config::parse_value(key, value)?,
+                    "level" => game.level = config::parse_value(key, value)?,
+                    "erase_lines" => game.erase_lines = config::parse_value(key, value)?,
+                    "erase_diagonal_lines" => {
+                        game.erase_diagonal_lines = config::parse_value(key, value)?
+                    }
+                    "soft_drop" => game.soft_drop = config::parse_value(key, value)?,
+                    "landed" => game.landed = config::parse_value(key, value)?,
+                    "magic_pending" => game.magic_pending = config::parse_value(key, value)?,
+                    "fall_wait" => game.fall_wait = config::parse_value(key, value)?,
+                    "spawn_wait" => game.spawn_wait = config::parse_value(key, value)?,
+                    "flashing_wait" => game.flashing_wait = config::parse_value(key, value)?,
+                    "piece_fall_wait" => game.piece_fall_wait = config::parse_value(key, value)?,
+                    "piece_fall_offset" => {
+                        game.piece_fall_offset = config::parse_value(key, value)?
+                    }
+                    "pressure_wait" => game.pressure_wait = config::parse_value(key, value)?,
+                    _ => return Err(format!("unknown state: {}", key)),
+                }
+            } else if let Some((grid, y)) = key.split_once('.') {
+                if !["field", "check_erase_result", "piece_falling"].contains(&grid) {
+                    continue;
+                }
+                let y: usize = config::parse_value(key, y)?;
+                let row: Vec<i32> = split(key, value)?;
+                if y >= game.rules.field_h || row.len() != game.rules.field_w {
+                    return Err(format!("`{}` does not fit the field", key));
+                }
+                match grid {
+                    "field" => game.field[y] = row,
+                    "check_erase_result" => {
+                        game.check_erase_result[y] = row.iter().map(|&v| v != 0).collect()
+                    }
+                    _ => game.piece_falling[y] = row.iter().map(|&v| v != 0).collect(),
+                }
+            }
+        }
+
+        if game.mode == Mode::Puzzle {
+            let stage = Stage::from_entries(&entries, "stage", "", game.rules.clone())?;
+            let mut puzzle = Puzzle::new(stage);
+            puzzle.next_piece = config::parse_value("next_piece", get("puzzle.next_piece")?)?;
+            puzzle.target = match split::<usize>("target", get("puzzle.target")?)?.as_slice() {
+                [] => None,
+                &[x, y] if x < game.rules.field_w && y < game.rules.field_h => Some((x, y)),
+                _ => return Err("puzzle target is outside of the field".to_string()),
+            };
+            puzzle.cleared = config::parse_value("cleared", get("puzzle.cleared")?)?;
+            if puzzle.next_piece > puzzle.stage.pieces.len() {
+                return Err("puzzle has fewer pieces than already played".to_string());
+            }
+            game.puzzle = Some(puzzle);
+        }
+        if game.mode.is_timed() {
+            game.timer = Some(Timer {
+                countdown: config::parse_value("countdown", get("timer.countdown")?)?,
+                frames: config::parse_value("frames", get("timer.frames")?)?,
+                finished: config::parse_value("finished", get("timer.finished")?)?,
+            });
+        }
+
+        let block_len = game.rules.block_len;
+        if [&game.current, &game.next, &game.next_to_display]
+            .iter()
+            .any(|piece| piece.len() != block_len)
+        {
+            return Err(format!("pieces must have {} jewels", block_len));
+        }
+        if game.current_x >= game.rules.field_w || game.current_y + block_len > game.rules.field_h {
+            return Err("current piece is outside of the field".to_string());
+        }
+
+        let frames = (game.frame + 1).max(0) as usize;
+        game.history = decode_inputs(history, frames).map_err(|e| format!("history {}", e))?;
+        game.commands =
+            decode_inputs(commands, MAX_FRAMES).map_err(|e| format!("commands {}", e))?;
+        game.checksums = decode_checksums(checksums).map_err(|e| format!("checksums {}", e))?;
+        // ゲームオーバー後のフレームは履歴に残らない
+        let frames = game.history.len() as i32;
+        if frames > game.frame + 1 || (!game.is_over && frames != game.frame + 1) {
+            return Err(format!(
+                "history has {} frames, but frame is {}",
+                game.history.len(),
+                game.frame
+            ));
+        }
+        Ok(game)
+    }
+
+    /// ディレクトリが無ければ作る
+    pub fn save_snapshot(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        }
+        std::fs::write(path, self.to_snapshot())
+            .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+    }
+
+    pub fn load_snapshot(path: &Path) -> Result<Game, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Game::from_snapshot(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::{Bot, RandomBot};
+    use crate::model::Command;
+
+    #[test]
+    fn test_round_trip() {
+        let rules = Rules::preset("magic").unwrap();
+        let mut game = Game::with_seed(5, rules).with_mode(Mode::Pressure);
+        let mut bot = RandomBot::new(5);
+        // 消去中や落下中の状態も含めていろいろな時点で保存して比べる
+        for _ in 0..40 {
+            for _ in 0..37 {
+                game.update(bot.input(&game));
+            }
+            game.requested_sounds.clear();
+            let restored = Game::from_snapshot(&game.to_snapshot()).unwrap();
+            assert_eq!(format!("{:?}", restored), format!("{:?}", game));
+        }
+
+        // 復元したゲームは同じ入力で同じように進む
+        let mut restored = Game::from_snapshot(&game.to_snapshot()).unwrap();
+        for _ in 0..500 {
+            let input = bot.input(&game);
+            game.update(input);
+            restored.update(input);
+        }
+        assert_eq!(format!("{:?}", restored), format!("{:?}", game));
+
+        // カウントダウン中に中断しても続きから数える
+        let mut game = Game::with_seed(5, Rules::default()).with_mode(Mode::Sprint);
+        for _ in 0..10 {
+            game.update(Command::None);
+        }
+        let restored = Game::from_snapshot(&game.to_snapshot()).unwrap();
+        assert_eq!(restored.timer, game.timer);
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut game = Game::with_seed(1, Rules::default());
+        for _ in 0..100 {
+            game.update(Command::None);
+        }
+        let text = game.to_snapshot();
+        assert!(Game::from_snapshot(&text).is_ok());
+        let broken = [
+            text.replace("format_version = 1", "format_version = 0"),
+            text.replace("[history]", ""),
+            text.replace("\n15 = \"", "\n15 = \"0 "),
+            text.replace("frame = 99", "frame = 98"),
+            text.replace("state = \"Controllable\"", "state = \"Sleeping\""),
+        ];
+        for text in broken {
+            assert!(Game::from_snapshot(&text).is_err());
+        }
+    }
+}
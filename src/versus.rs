@@ -0,0 +1,187 @@
+//! Two-player versus mode.
+//!
+//! Both players play their own [`Game`] with the same seed, so they get the
+//! same sequence of pieces. When a chain ends, the player who made it sends
+//! garbage to the other: rows of jewels that rise from the bottom of the
+//! field the next time a piece of the receiver settles. Garbage sent while
+//! the sender has garbage coming cancels it out first. The first player
+//! whose field overflows (see [`Game::check_gameover`] and
+//! [`Game::push_rows`]) loses.
+
+use crate::model::{garbage_row, Command, Game, Input, State};
+use crate::rules::Rules;
+use rand::prelude::*;
+
+pub const PLAYERS: usize = 2;
+/// 1回の着地でせり上がる段数。残りは次の着地に回す
+pub const MAX_RISE: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Winner(usize),
+    Draw, // 同じフレームに両方あふれた
+}
+
+/// 連鎖が終わったときに送るおじゃまの段数。2連鎖目から1段ずつと、一度に消した宝石6個ごとに1段
+pub fn attack(combo: i32, erased: i32) -> i32 {
+    combo.max(0) + erased / 6
+}
+
+#[derive(Debug, Clone)]
+pub struct Versus {
+    pub seed: u64,
+    pub players: [Game; PLAYERS],
+    pub garbage: [i32; PLAYERS], // 受け取る予定の段数
+    pub outcome: Option<Outcome>,
+    rng: StdRng, // おじゃまの色。ピースの順番を変えないよう別にする
+}
+
+impl Versus {
+    pub fn new(seed: u64, rules: Rules) -> Self {
+        let game = Game::with_seed(seed, rules);
+        Versus {
+            seed,
+            players: [game.clone(), game],
+            garbage: [0; PLAYERS],
+            outcome: None,
+            rng: StdRng::seed_from_u64(seed.wrapping_add(1)),
+        }
+    }
+
+    /// 1フレーム進める。一時停止とコマ送りはどちらが押しても二人とも
+    pub fn update(&mut self, mut inputs: [Input; PLAYERS]) {
+        if self.outcome.is_some() {
+            return;
+        }
+        for command in [Command::Pause, Command::Step] {
+            if inputs.iter().any(|input| input.contains(command)) {
+                inputs.iter_mut().for_each(|input| input.insert(command));
+            }
+        }
+
+        for (i, input) in inputs.into_iter().enumerate() {
+            let game = &mut self.players[i];
+            let (state, combo, erased) = (game.state, game.combo, game.erased_one_time);
+            let settles = game.settles;
+            game.update(input);
+
+            // 連鎖が終わったら、自分に来ているぶんを相殺してから相手に送る
+            if state != State::Controllable && game.state == State::Controllable {
+                let sent = attack(combo, erased);
+                let cancelled = sent.min(self.garbage[i]);
+                self.garbage[i] -= cancelled;
+                self.garbage[1 - i] += sent - cancelled;
+            }
+            // 着地して次のピースが出たところでせり上げる
+            let game = &mut self.players[i];
+            if game.settles > settles && !game.is_over && self.garbage[i] > 0 {
+                let rows = self.garbage[i].min(MAX_RISE);
+                self.garbage[i] -= rows;
+                rise(game, &mut self.rng, rows as usize);
+            }
+        }
+
+        self.outcome = match (self.players[0].is_over, self.players[1].is_over) {
+            (true, true) => Some(Outcome::Draw),
+            (true, false) => Some(Outcome::Winner(1)),
+            (false, true) => Some(Outcome::Winner(0)),
+            (false, false) => None,
+        };
+    }
+}
+
+/// 下から rows 段のおじゃまを足して押し上げる
+fn rise(game: &mut Game, rng: &mut StdRng, rows: usize) {
+    let color_count = game.rules.color_count;
+    let mut above = game.field[game.rules.field_h - 1].clone();
+    let mut garbage = Vec::with_capacity(rows);
+    for _ in 0..rows {
+        above = garbage_row(&above, || rng.gen_range(1..=color_count));
+        garbage.push(above.clone());
+    }
+    game.push_rows(&garbage);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::{AiBot, Weights};
+    use crate::bot::Bot;
+    use crate::model::EMPTY;
+
+    fn none() -> [Input; PLAYERS] {
+        [Input::NONE; PLAYERS]
+    }
+
+    #[test]
+    fn test_same_pieces() {
+        let mut versus = Versus::new(4, Rules::default());
+        for i in 0..200 {
+            let mut inputs = none();
+            if i % 10 == 0 {
+                inputs[0] = Command::HardDrop.into();
+            }
+            versus.update(inputs);
+        }
+        // 落とした数は違っても、同じ色の列から順に出てくる
+        let [a, b] = &versus.players;
+        assert!(a.rng_draws > b.rng_draws);
+        let mut game = Game::with_seed(4, Rules::default());
+        game.restore_rng(0);
+        let colors: Vec<i32> = (0..a.rng_draws).map(|_| game.draw_color()).collect();
+        for player in [a, b] {
+            let end = player.rng_draws as usize;
+            assert_eq!(player.next, colors[end - player.next.len()..end]);
+        }
+    }
+
+    #[test]
+    fn test_garbage() {
+        assert_eq!(attack(0, 3), 0);
+        // 連鎖しなくても一度に6個消せば1段送る
+        assert_eq!(attack(0, 5), 0);
+        assert_eq!(attack(0, 6), 1);
+        assert_eq!(attack(2, 9), 3);
+
+        let mut versus = Versus::new(1, Rules::default());
+        versus.garbage[1] = 4;
+        while versus.players[1].settles == 0 {
+            versus.update([Input::NONE, Command::HardDrop.into()]);
+        }
+        let field = &versus.players[1].field;
+        let bottom = field.len() - MAX_RISE as usize;
+        assert!(field[bottom..]
+            .iter()
+            .all(|row| row.iter().all(|&v| v != EMPTY)));
+        assert_eq!(versus.garbage[1], 1);
+        assert_eq!(versus.outcome, None);
+    }
+
+    #[test]
+    fn test_outcome() {
+        let mut versus = Versus::new(2, Rules::default());
+        versus.update([Command::Pause.into(), Input::NONE]);
+        assert!(versus.players.iter().all(|game| game.paused));
+        versus.update([Command::Pause.into(), Input::NONE]);
+
+        versus.garbage[0] = 100;
+        while versus.outcome.is_none() {
+            versus.update([Command::HardDrop.into(), Input::NONE]);
+        }
+        assert_eq!(versus.outcome, Some(Outcome::Winner(1)));
+        let frame = versus.players[1].frame;
+        versus.update(none());
+        assert_eq!(versus.players[1].frame, frame);
+    }
+
+    #[test]
+    fn test_ai_player() {
+        let mut versus = Versus::new(3, Rules::default());
+        let mut ai = AiBot::new(Weights::default()).with_delay(2);
+        while versus.outcome.is_none() {
+            let input = ai.input(&versus.players[1]);
+            versus.update([Input::NONE, input]);
+        }
+        assert_eq!(versus.outcome, Some(Outcome::Winner(1)));
+    }
+}
@@ -0,0 +1,262 @@
+//! Replay viewer: pause, playback speed, frame step, seek and rewind.
+//!
+//! The replay is played through once when it is loaded, keeping a copy of
+//! the game every [`CHECKPOINT_INTERVAL`] frames and noting where chains
+//! start. Seeking restarts from the last checkpoint before the target, so a
+//! jump anywhere costs at most one interval of simulation. Playback stops at
+//! the end of the recording instead of going on with live input. The same
+//! pass compares the checksums of the replay (see [`crate::desync`]).
+
+use crate::desync::Desync;
+use crate::model::{Game, Input, State, FPS};
+use crate::replay::Replay;
+
+/// 再生速度の倍率
+pub const SPEEDS: [f32; 7] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0];
+const NORMAL_SPEED: usize = 2;
+
+pub const CHECKPOINT_INTERVAL: i32 = FPS * 10;
+
+#[derive(Debug)]
+pub struct Viewer {
+    pub game: Game,
+    pub paused: bool,
+    speed: usize,               // SPEEDS の添字
+    progress: f32,              // 1 になったら次のフレームに進む
+    length: i32,                // 記録されているフレーム数
+    checkpoints: Vec<Game>,     // i 番目は i * CHECKPOINT_INTERVAL フレーム進めたところ
+    chains: Vec<i32>,           // 連鎖が始まった位置
+    pub desync: Option<Desync>, // 記録と食い違った最初の着地
+}
+
+impl Viewer {
+    pub fn new(replay: &Replay) -> Self {
+        let mut game = Game::from_replay(replay);
+        game.requested_sounds.clear();
+        let length = replay.commands.len() as i32;
+        let mut checkpoints = vec![game.clone()];
+        let mut chains = Vec::new();
+        for position in 1..=length {
+            let state = game.state;
+            game.update(Input::NONE);
+            game.requested_sounds.clear();
+            // 操作中から消去に移ったところが連鎖の始まり
+            if state == State::Controllable && game.state == State::Flashing {
+                chains.push(position);
+            }
+            if position % CHECKPOINT_INTERVAL == 0 {
+                checkpoints.push(game.clone());
+            }
+        }
+        let desync = Desync::find(&replay.checksums, &game.checksums);
+        Viewer {
+            game: checkpoints[0].clone(),
+            paused: false,
+            speed: NORMAL_SPEED,
+            progress: 0.0,
+            length,
+            checkpoints,
+            chains,
+            desync,
+        }
+    }
+
+    /// 進めたフレーム数。0 から length まで
+    pub fn position(&self) -> i32 {
+        self.game.frame + 1
+    }
+
+    pub fn length(&self) -> i32 {
+        self.length
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position() >= self.length
+    }
+
+    pub fn speed(&self) -> f32 {
+        SPEEDS[self.speed]
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = self.speed.saturating_sub(1);
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// 画面の1フレームぶん、速度に応じて進める。終わりまで来たら一時停止する
+    pub fn tick(&mut self) {
+        if self.paused {
+            return;
+        }
+        self.progress += self.speed();
+        while self.progress >= 1.0 && !self.is_finished() {
+            self.advance();
+            self.progress -= 1.0;
+        }
+        // 早送り中は効果音が重なりすぎるので鳴らさない
+        if self.speed() > 1.0 {
+            self.game.requested_sounds.clear();
+        }
+        if self.is_finished() {
+            self.paused = true;
+            self.progress = 0.0;
+        }
+    }
+
+    /// 1フレームだけ進める
+    pub fn step(&mut self) {
+        if !self.is_finished() {
+            self.advance();
+        }
+    }
+
+    pub fn step_back(&mut self) {
+        self.seek(self.position() - 1);
+    }
+
+    /// 指定した位置まで飛ぶ。範囲外は端に合わせる
+    pub fn seek(&mut self, position: i32) {
+        let position = position.clamp(0, self.length);
+        let index = ((position / CHECKPOINT_INTERVAL) as usize).min(self.checkpoints.len() - 1);
+        let checkpoint = index as i32 * CHECKPOINT_INTERVAL;
+        if position < self.position() || checkpoint > self.position() {
+            self.game = self.checkpoints[index].clone();
+        }
+        while self.position() < position {
+            self.advance();
+        }
+        self.game.requested_sounds.clear();
+        self.progress = 0.0;
+    }
+
+    /// 秒数で前後に動く。負なら巻き戻し
+    pub fn seek_by(&mut self, seconds: i32) {
+        self.seek(self.position() + seconds * FPS);
+    }
+
+    /// 食い違いが分かった位置を過ぎたか
+    pub fn is_desynced(&self) -> bool {
+        self.desync
+            .as_ref()
+            .is_some_and(|desync| self.position() > desync.frame())
+    }
+
+    pub fn next_chain(&mut self) {
+        if let Some(&position) = self.chains.iter().find(|&&p| p > self.position()) {
+            self.seek(position);
+        }
+    }
+
+    pub fn previous_chain(&mut self) {
+        if let Some(&position) = self.chains.iter().rev().find(|&&p| p < self.position()) {
+            self.seek(position);
+        }
+    }
+
+    fn advance(&mut self) {
+        self.game.update(Input::NONE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::{Bot, RandomBot};
+    use crate::model::Command;
+    use crate::rules::Rules;
+
+    fn replay() -> Replay {
+        let mut game = Game::with_seed(3, Rules::preset("wide").unwrap());
+        let mut bot = RandomBot::new(3);
+        // すぐ積み上がらないようハードドロップは使わない
+        while !game.is_over && game.frame < FPS * 60 {
+            let mut input = bot.input(&game);
+            input.remove(Command::HardDrop);
+            game.update(input);
+        }
+        Replay::from_game(&game, "tester")
+    }
+
+    // 効果音の要求だけは比べない
+    fn state(game: &Game) -> String {
+        let mut game = game.clone();
+        game.requested_sounds.clear();
+        format!("{:?}", game)
+    }
+
+    #[test]
+    fn test_seek() {
+        let replay = replay();
+        let mut viewer = Viewer::new(&replay);
+        let mut expected = vec![state(&viewer.game)];
+        while !viewer.is_finished() {
+            viewer.step();
+            expected.push(state(&viewer.game));
+        }
+        assert_eq!(viewer.length(), replay.commands.len() as i32);
+
+        // 前後どちらに飛んでも、頭から再生したのと同じ状態になる
+        for position in [350, 20, 0, 301, 300, 299, viewer.length(), 1] {
+            viewer.seek(position);
+            assert_eq!(viewer.position(), position);
+            assert_eq!(state(&viewer.game), expected[position as usize]);
+        }
+        viewer.step_back();
+        assert_eq!(viewer.position(), 0);
+        viewer.seek_by(-5);
+        assert_eq!(viewer.position(), 0);
+        viewer.seek_by(5);
+        assert_eq!(viewer.position(), 5 * FPS);
+
+        viewer.seek(0);
+        viewer.next_chain();
+        let chain = viewer.position();
+        assert!(chain > 0);
+        assert_eq!(viewer.game.state, State::Flashing);
+        viewer.next_chain();
+        viewer.previous_chain();
+        assert_eq!(viewer.position(), chain);
+        assert_eq!(viewer.desync, None);
+        assert!(!viewer.is_desynced());
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut viewer = Viewer::new(&replay());
+        viewer.slower();
+        viewer.slower();
+        viewer.slower();
+        assert_eq!(viewer.speed(), 0.25);
+        for _ in 0..8 {
+            viewer.tick();
+        }
+        assert_eq!(viewer.position(), 2);
+
+        viewer.toggle_pause();
+        viewer.tick();
+        assert_eq!(viewer.position(), 2);
+        viewer.toggle_pause();
+
+        for _ in 0..10 {
+            viewer.faster();
+        }
+        assert_eq!(viewer.speed(), 16.0);
+        viewer.tick();
+        assert_eq!(viewer.position(), 18);
+
+        // 終わりで止まり、それ以上は進まない
+        while !viewer.paused {
+            viewer.tick();
+        }
+        assert!(viewer.is_finished());
+        viewer.step();
+        assert_eq!(viewer.position(), viewer.length());
+    }
+}